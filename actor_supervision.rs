@@ -1,7 +1,11 @@
-
-std::time::Duration;
+use std::time::Duration;
 use tokio::sync::mpsc;
-use tokio::time::sleep;
+use tokio::task::JoinHandle;
+use tokio::time::{interval, sleep};
+
+// Every worker ever started gets its own ID; a restarted worker gets a new
+// one, so late messages from the one it replaced can be told apart.
+type WorkerId = u32;
 
 // Message types for our actor system
 #[derive(Debug)]
@@ -13,93 +17,159 @@ enum WorkerMessage {
 
 #[derive(Debug)]
 enum SupervisorMessage {
-    WorkerResult(u32),
-    WorkerError(String),
+    WorkerResult { id: WorkerId, result: u32 },
+    WorkerError { id: WorkerId, error: String },
+}
+
+// A running worker as the supervisor sees it
+struct Worker {
+    id: WorkerId,
+    sender: mpsc::Sender<WorkerMessage>,
+    handle: JoinHandle<()>,
 }
 
 // Worker actor - similar to Erlang process
 async fn worker_actor(
+    id: WorkerId,
     mut receiver: mpsc::Receiver<WorkerMessage>,
     supervisor: mpsc::Sender<SupervisorMessage>,
 ) {
-    println!("Worker started!");
+    println!("Worker {} started!", id);
 
     while let Some(msg) = receiver.recv().await {
         match msg {
             WorkerMessage::DoWork(n) => {
-                println!("Worker processing: {}", n);
+                println!("Worker {} processing: {}", id, n);
                 sleep(Duration::from_millis(500)).await;
 
                 let result = n * 2;
-                if let Err(_) = supervisor
-                    .send(SupervisorMessage::WorkerResult(result))
+                if supervisor
+                    .send(SupervisorMessage::WorkerResult { id, result })
                     .await
+                    .is_err()
                 {
-                    println!("Supervisor appears to be down!");
+                    println!("Worker {}: supervisor appears to be down!", id);
                     return;
                 }
             }
             WorkerMessage::Crash => {
-                println!("Worker is crashing!");
-                if let Err(_) = supervisor
-                    .send(SupervisorMessage::WorkerError(
-                        "Worker crashed!".to_string(),
-                    ))
+                println!("Worker {} is crashing!", id);
+                if supervisor
+                    .send(SupervisorMessage::WorkerError {
+                        id,
+                        error: "Worker crashed!".to_string(),
+                    })
                     .await
+                    .is_err()
                 {
-                    println!("Failed to notify supervisor about crash!");
+                    println!("Worker {} failed to notify supervisor about crash!", id);
                 }
                 return;
             }
             WorkerMessage::Status => {
-                println!("Worker is healthy!");
+                println!("Worker {} is healthy!", id);
             }
         }
     }
 }
 
-// Supervisor actor - similar to Erlang supervisor
-async fn supervisor(worker_count: u32) {
-    println!("Supervisor started!");
-
-    let (sup_tx, mut sup_rx) = mpsc::channel::<SupervisorMessage>(100);
-    let mut workers = vec![];
-
-    // Start initial workers
-    for id in 0..worker_count {
-        let (worker_tx, worker_rx) = mpsc::channel::<WorkerMessage>(100);
-        let sup_tx_clone = sup_tx.clone();
+fn spawn_worker(id: WorkerId, supervisor: mpsc::Sender<SupervisorMessage>) -> Worker {
+    let (sender, receiver) = mpsc::channel::<WorkerMessage>(100);
+    let handle = tokio::spawn(worker_actor(id, receiver, supervisor));
+    Worker { id, sender, handle }
+}
 
-        // Spawn worker
-        let worker_handle = tokio::spawn(worker_actor(worker_rx, sup_tx_clone));
-        workers.push((worker_tx, worker_handle));
+// Supervisor actor - similar to Erlang supervisor. Workers report to it on
+// `sender`; `supervise` feeds it what arrives.
+struct Supervisor {
+    workers: Vec<Worker>,
+    next_id: WorkerId,
+    sender: mpsc::Sender<SupervisorMessage>,
+}
 
-        // Send initial work
-        let tx = &workers[id as usize].0;
-        tx.send(WorkerMessage::DoWork(id)).await.unwrap();
+impl Supervisor {
+    // Starts `worker_count` workers, each with some initial work, and returns
+    // the supervisor with the receiving end of its channel.
+    async fn start(worker_count: u32) -> (Supervisor, mpsc::Receiver<SupervisorMessage>) {
+        println!("Supervisor started!");
+        let (sender, receiver) = mpsc::channel::<SupervisorMessage>(100);
+        let mut supervisor = Supervisor { workers: vec![], next_id: 0, sender };
+
+        for n in 0..worker_count {
+            let worker = spawn_worker(supervisor.next_id, supervisor.sender.clone());
+            supervisor.next_id += 1;
+
+            // Send initial work
+            worker.sender.send(WorkerMessage::DoWork(n)).await.unwrap();
+            supervisor.workers.push(worker);
+        }
+        (supervisor, receiver)
     }
 
-    // Supervision loop
-    while let Some(msg) = sup_rx.recv().await {
+    async fn handle(&mut self, msg: SupervisorMessage) {
         match msg {
-            SupervisorMessage::WorkerResult(n) => {
-                println!("Supervisor received result: {}", n);
+            SupervisorMessage::WorkerResult { id, result } => {
+                println!("Supervisor received result from worker {}: {}", id, result);
+            }
+            SupervisorMessage::WorkerError { id, error } => {
+                println!("Supervisor received error from worker {}: {}", id, error);
+                match self.workers.iter().position(|w| w.id == id) {
+                    Some(slot) => {
+                        println!("Restarting worker {}...", id);
+                        self.restart(slot).await;
+                    }
+                    // already reaped and replaced
+                    None => println!("Worker {} was already replaced", id),
+                }
+            }
+        }
+    }
+
+    // Replaces workers that exited without reporting (a panic, say).
+    async fn reap(&mut self) {
+        for slot in 0..self.workers.len() {
+            if self.workers[slot].handle.is_finished() {
+                println!("Worker {} exited without reporting", self.workers[slot].id);
+                self.restart(slot).await;
             }
-            SupervisorMessage::WorkerError(err) => {
-                println!("Supervisor received error: {}", err);
-                println!("Restarting worker...");
-
-                // Restart the worker
-                let (worker_tx, worker_rx) = mpsc::channel::<WorkerMessage>(100);
-                let sup_tx_clone = sup_tx.clone();
-                let worker_handle = tokio::spawn(worker_actor(worker_rx, sup_tx_clone));
-                workers.push((worker_tx, worker_handle));
-
-                // Send test message to new worker
-                let tx = workers.last().unwrap().0.clone();
-                tx.send(WorkerMessage::Status).await.unwrap();
+        }
+    }
+
+    // Puts a fresh worker in `slot` and reaps the one it replaces, so dead
+    // senders and handles don't pile up.
+    async fn restart(&mut self, slot: usize) {
+        let worker = spawn_worker(self.next_id, self.sender.clone());
+        self.next_id += 1;
+        let old = std::mem::replace(&mut self.workers[slot], worker);
+        println!("Replaced worker {} with worker {} in slot {}", old.id, self.workers[slot].id, slot);
+
+        // the old task has returned or is about to; abort is a no-op if so
+        old.handle.abort();
+        if let Err(e) = old.handle.await {
+            if e.is_panic() {
+                println!("Worker {} panicked: {}", old.id, e);
             }
         }
+
+        // Send test message to new worker
+        let tx = self.workers[slot].sender.clone();
+        tx.send(WorkerMessage::Status).await.unwrap();
+    }
+}
+
+// Supervision loop
+async fn supervise(mut supervisor: Supervisor, mut receiver: mpsc::Receiver<SupervisorMessage>) {
+    // Workers that exit without reporting are found here
+    let mut reap = interval(Duration::from_secs(1));
+
+    loop {
+        tokio::select! {
+            msg = receiver.recv() => match msg {
+                Some(msg) => supervisor.handle(msg).await,
+                None => break,
+            },
+            _ = reap.tick() => supervisor.reap().await,
+        }
     }
 }
 
@@ -108,7 +178,13 @@ async fn main() {
     println!("Starting actor system...");
 
     // Start supervisor with 2 workers
-    let supervisor_handle = tokio::spawn(supervisor(2));
+    let (supervisor, receiver) = Supervisor::start(2).await;
+
+    // Crash the first worker once it's done, to show a restart
+    if let Some(worker) = supervisor.workers.first() {
+        worker.sender.send(WorkerMessage::Crash).await.unwrap();
+    }
+    let supervisor_handle = tokio::spawn(supervise(supervisor, receiver));
 
     // Let the system run for a while
     sleep(Duration::from_secs(2)).await;
@@ -116,3 +192,36 @@ async fn main() {
     // Keep main alive
     supervisor_handle.await.unwrap();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_crash_replaces_the_slot() {
+        let (mut supervisor, mut receiver) = Supervisor::start(3).await;
+        let ids: Vec<WorkerId> = supervisor.workers.iter().map(|w| w.id).collect();
+        supervisor.workers[1].sender.send(WorkerMessage::Crash).await.unwrap();
+
+        // results come first, then the crash
+        loop {
+            let msg = receiver.recv().await.unwrap();
+            let crashed = matches!(msg, SupervisorMessage::WorkerError { id: 1, .. });
+            supervisor.handle(msg).await;
+            if crashed {
+                break;
+            }
+        }
+        assert_eq!(supervisor.workers.len(), 3);
+        assert_eq!((supervisor.workers[0].id, supervisor.workers[2].id), (ids[0], ids[2]));
+        assert!(!ids.contains(&supervisor.workers[1].id));
+        assert!(!supervisor.workers[1].handle.is_finished());
+    }
+
+    #[tokio::test]
+    async fn test_no_workers() {
+        let (mut supervisor, _receiver) = Supervisor::start(0).await;
+        supervisor.reap().await;
+        assert!(supervisor.workers.is_empty());
+    }
+}
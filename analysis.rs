@@ -0,0 +1,166 @@
+use crate::SAMPLE_RATE;
+use img::{Rgb, RgbImage};
+use rustfft::num_complex::Complex;
+use rustfft::FftPlanner;
+use std::f64::consts::PI;
+
+// Dark blue through red to pale yellow, from silence to full scale.
+const HEAT: [[f64; 3]; 5] = [
+    [0.0, 0.0, 0.0],
+    [30.0, 10.0, 90.0],
+    [180.0, 30.0, 70.0],
+    [250.0, 140.0, 20.0],
+    [255.0, 250.0, 200.0],
+];
+
+fn heat(x: f64) -> Rgb<u8> {
+    let x = x.clamp(0.0, 1.0) * (HEAT.len() - 1) as f64;
+    let i = (x.floor() as usize).min(HEAT.len() - 2);
+    let f = x - i as f64;
+    let c = |k: usize| (HEAT[i][k] + (HEAT[i + 1][k] - HEAT[i][k]) * f).round() as u8;
+    Rgb([c(0), c(1), c(2)])
+}
+
+// Short-time Fourier transform settings. Each column of the image is one
+// Hann-windowed frame `hop` samples after the last; rows run from 0 Hz at the
+// bottom to Nyquist at the top, on a log scale from `min_freq` if `log_freq`.
+#[derive(Clone, Debug)]
+pub struct Spectrogram {
+    pub window: usize,
+    pub hop: usize,
+    pub height: usize,
+    // magnitude shown as black, relative to the loudest bin (or to
+    // `QUIET_PEAK_DB`, if nothing is louder)
+    pub floor_db: f64,
+    pub log_freq: bool,
+    pub min_freq: f64,
+}
+
+// The quietest peak `render` scales to, so near-silence stays dark rather
+// than having its noise floor stretched to full brightness.
+const QUIET_PEAK_DB: f64 = -120.0;
+
+impl Default for Spectrogram {
+    fn default() -> Self {
+        Spectrogram { window: 2048, hop: 256, height: 512, floor_db: -90.0, log_freq: true, min_freq: 20.0 }
+    }
+}
+
+impl Spectrogram {
+    // Magnitude in dB of each bin (0..=window/2) of each frame.
+    pub fn frames(&self, samples: &[f64]) -> Vec<Vec<f64>> {
+        let n = self.window.max(2);
+        let fft = FftPlanner::new().plan_fft_forward(n);
+        let hann: Vec<f64> = (0..n).map(|i| 0.5 - 0.5 * (2.0 * PI * i as f64 / n as f64).cos()).collect();
+        let count = samples.len().saturating_sub(n) / self.hop.max(1) + 1;
+        (0..count)
+            .map(|frame| {
+                let start = frame * self.hop.max(1);
+                let mut buf: Vec<Complex<f64>> = (0..n)
+                    .map(|i| Complex::new(samples.get(start + i).copied().unwrap_or(0.0) * hann[i], 0.0))
+                    .collect();
+                fft.process(&mut buf);
+                // Hann's coherent gain is 1/2, so a full-scale sine peaks at 0 dB
+                buf[..=n / 2].iter().map(|c| 20.0 * (c.norm() * 4.0 / n as f64 + 1e-12).log10()).collect()
+            })
+            .collect()
+    }
+
+    // Frequency shown on image row `row`, counted from the bottom.
+    pub fn row_freq(&self, row: usize) -> f64 {
+        let nyquist = SAMPLE_RATE as f64 / 2.0;
+        let x = (row as f64 + 0.5) / self.height as f64;
+        if self.log_freq {
+            self.min_freq * (nyquist / self.min_freq).powf(x)
+        } else {
+            nyquist * x
+        }
+    }
+
+    pub fn render(&self, samples: &[f64]) -> RgbImage {
+        let frames = self.frames(samples);
+        let bins = frames[0].len();
+        let peak = frames.iter().flatten().fold(QUIET_PEAK_DB, |m, &db| m.max(db));
+        let bin_hz = SAMPLE_RATE as f64 / self.window.max(2) as f64;
+        let rows: Vec<usize> = (0..self.height).map(|row| ((self.row_freq(row) / bin_hz).round() as usize).min(bins - 1)).collect();
+        let height = self.height as u32;
+        RgbImage::from_fn(frames.len() as u32, height, |x, y| {
+            let db = frames[x as usize][rows[(height - 1 - y) as usize]] - peak;
+            heat(1.0 - db / self.floor_db)
+        })
+    }
+}
+
+// Oscilloscope view: each column spans the min and max of the samples that
+// fall in it (and the one before, so the trace is continuous), over a faint
+// centre line.
+pub fn waveform(samples: &[f64], width: u32, height: u32) -> RgbImage {
+    let mut image = RgbImage::from_pixel(width, height, Rgb([10, 12, 16]));
+    let mid = height / 2;
+    for x in 0..width {
+        image.put_pixel(x, mid, Rgb([50, 60, 70]));
+    }
+    let row = |v: f64| ((1.0 - v.clamp(-1.0, 1.0)) * 0.5 * (height - 1) as f64).round() as u32;
+    for x in 0..width as usize {
+        let from = x * samples.len() / width as usize;
+        let to = ((x + 1) * samples.len() / width as usize).max(from + 1).min(samples.len());
+        if from >= to {
+            continue;
+        }
+        let column = &samples[from.saturating_sub(1)..to];
+        let (lo, hi) = column.iter().fold((f64::MAX, f64::MIN), |(lo, hi), &v| (lo.min(v), hi.max(v)));
+        if lo == 0.0 && hi == 0.0 {
+            continue;
+        }
+        for y in row(hi)..=row(lo) {
+            image.put_pixel(x as u32, y, Rgb([120, 230, 140]));
+        }
+    }
+    image
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_spectrogram_finds_a_sine() {
+        let samples: Vec<f64> = (0..SAMPLE_RATE / 4).map(|i| (2.0 * PI * 1000.0 * i as f64 / SAMPLE_RATE as f64).sin()).collect();
+        let settings = Spectrogram { log_freq: false, ..Spectrogram::default() };
+        let frames = settings.frames(&samples);
+        assert_eq!(frames.len(), (samples.len() - 2048) / 256 + 1);
+        let loudest = frames[3].iter().enumerate().fold((0, f64::MIN), |m, (i, &db)| if db > m.1 { (i, db) } else { m });
+        assert_eq!(loudest.0, (1000.0 * 2048.0 / SAMPLE_RATE as f64).round() as usize);
+        // 1 kHz falls between bins, and Hann loses up to 1.4 dB there
+        assert!(loudest.1.abs() < 1.5, "{}", loudest.1);
+
+        let image = settings.render(&samples);
+        assert_eq!(image.dimensions(), (frames.len() as u32, 512));
+        // the brightest row is the one showing 1 kHz
+        let brightest = (0..512).max_by_key(|&y| image.get_pixel(3, y).0.iter().map(|&c| c as u32).sum::<u32>()).unwrap();
+        let freq = settings.row_freq(511 - brightest as usize);
+        assert!((freq - 1000.0).abs() < 30.0, "{freq}");
+    }
+
+    #[test]
+    fn test_spectrogram_of_silence_is_dark() {
+        let image = Spectrogram::default().render(&[0.0; 8192]);
+        assert!(image.pixels().all(|p| *p == heat(0.0)));
+
+        // a whisper is still drawn, relative to itself
+        let whisper: Vec<f64> = (0..8192).map(|i| 1e-4 * (2.0 * PI * 1000.0 * i as f64 / SAMPLE_RATE as f64).sin()).collect();
+        let image = Spectrogram::default().render(&whisper);
+        assert!(image.pixels().any(|p| *p == heat(1.0)));
+    }
+
+    #[test]
+    fn test_waveform_traces_the_envelope() {
+        let mut samples = vec![0.0; 1000];
+        samples.extend((0..1000).map(|i| if i % 2 == 0 { 1.0 } else { -1.0 }));
+        let image = waveform(&samples, 20, 11);
+        let lit = |x: u32| (0..11).filter(|&y| image.get_pixel(x, y).0 == [120, 230, 140]).count();
+        // silence only shows the centre line, full-scale noise fills the column
+        assert_eq!(lit(2), 0);
+        assert_eq!(lit(15), 11);
+    }
+}
@@ -0,0 +1,192 @@
+use crate::signal::Signal;
+use crate::{Limiter, SAMPLE_RATE};
+use std::f64::consts::TAU;
+
+// Anything that fills consecutive frames into a buffer, keeping whatever state
+// it needs between calls. Buffers can be any length, so the same processor
+// runs in a real-time callback and in an offline render.
+pub trait Block {
+    fn process(&mut self, out: &mut [f32]);
+}
+
+impl Block for Box<dyn Block> {
+    fn process(&mut self, out: &mut [f32]) {
+        (**self).process(out)
+    }
+}
+
+// Runs a `Signal` block by block. Time comes from an integer frame count, so
+// the nth frame is at exactly n / SAMPLE_RATE however the blocks are cut.
+pub struct Stream<S> {
+    signal: S,
+    frame: u64,
+}
+
+impl<S: Signal> Stream<S> {
+    pub fn new(signal: S) -> Self {
+        Stream { signal, frame: 0 }
+    }
+}
+
+impl<S: Signal> Block for Stream<S> {
+    fn process(&mut self, out: &mut [f32]) {
+        for y in out.iter_mut() {
+            *y = self.signal.at(self.frame as f64 / SAMPLE_RATE as f64) as f32;
+            self.frame += 1;
+        }
+    }
+}
+
+// Phase-accumulating oscillator over a 2π-periodic `shape` (`f64::sin`,
+// `tri`, `sharp`, `rabbit`, `sinc_osc`). The phase wraps every cycle, so it
+// stays precise over long renders, and `freq` can change between blocks
+// without a jump. Modulation, gates and envelopes have no block versions;
+// patches built from them run through `Stream` instead.
+pub struct Osc<F> {
+    shape: F,
+    pub freq: f64,
+    phase: f64,
+}
+
+impl<F: Fn(f64) -> f64> Osc<F> {
+    pub fn new(shape: F, freq: f64) -> Self {
+        Osc { shape, freq, phase: 0.0 }
+    }
+}
+
+impl<F: Fn(f64) -> f64> Block for Osc<F> {
+    fn process(&mut self, out: &mut [f32]) {
+        let step = TAU * self.freq / SAMPLE_RATE as f64;
+        for y in out.iter_mut() {
+            *y = (self.shape)(self.phase) as f32;
+            self.phase = (self.phase + step).rem_euclid(TAU);
+        }
+    }
+}
+
+pub fn sine(freq: f64) -> Osc<fn(f64) -> f64> {
+    Osc::new(f64::sin, freq)
+}
+
+pub fn tri(freq: f64) -> Osc<fn(f64) -> f64> {
+    Osc::new(crate::tri, freq)
+}
+
+pub fn sharp(sm: f64, freq: f64) -> Osc<impl Fn(f64) -> f64> {
+    Osc::new(move |phase| crate::sharp(sm, phase), freq)
+}
+
+pub fn rabbit(freq: f64) -> Osc<fn(f64) -> f64> {
+    Osc::new(crate::rabbit, freq)
+}
+
+// `sinc_osc` repeats every two cycles of `tri(t * impulse_freq * π)`, so it
+// runs at half the impulse frequency.
+pub fn sinc_osc(impulse_freq: f64, sinc_freq: f64) -> Osc<impl Fn(f64) -> f64> {
+    let scale = 2.0 * sinc_freq / impulse_freq;
+    Osc::new(move |phase| crate::sinc(crate::tri(phase) * scale), impulse_freq / 2.0)
+}
+
+// Another block's output through the limiter, for when there is no whole
+// signal to normalise first. NaN/inf frames become silence, as in `master`.
+pub struct Limited<B> {
+    inner: B,
+    limiter: Limiter,
+}
+
+impl<B: Block> Limited<B> {
+    pub fn new(inner: B, ceiling: f64) -> Self {
+        Limited { inner, limiter: Limiter::new(ceiling, 0.05) }
+    }
+}
+
+impl<B: Block> Block for Limited<B> {
+    fn process(&mut self, out: &mut [f32]) {
+        self.inner.process(out);
+        for y in out.iter_mut() {
+            let x = if y.is_finite() { *y as f64 } else { 0.0 };
+            *y = self.limiter.process(x) as f32;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::signal;
+    use std::f64::consts::PI;
+
+    fn run<B: Block>(block: &mut B, frames: usize, sizes: &[usize]) -> Vec<f32> {
+        let mut out = vec![0.0; frames];
+        let mut at = 0;
+        for &size in sizes.iter().cycle() {
+            if at == frames {
+                break;
+            }
+            let end = (at + size).min(frames);
+            block.process(&mut out[at..end]);
+            at = end;
+        }
+        out
+    }
+
+    #[test]
+    fn test_stream_matches_render() {
+        let rendered = crate::render(0.1, signal::tri(330.0).fm(signal::sine(3.0), 0.01));
+        let mut stream = Stream::new(signal::tri(330.0).fm(signal::sine(3.0), 0.01));
+        let streamed = run(&mut stream, rendered.len(), &[1, 64, 5, 1000, 127]);
+        assert!(rendered.iter().zip(&streamed).all(|(a, b)| *a as f32 == *b));
+    }
+
+    #[test]
+    fn test_osc_is_independent_of_block_size() {
+        let one = run(&mut tri(440.0), 10_000, &[10_000]);
+        let many = run(&mut tri(440.0), 10_000, &[1, 3, 64, 511]);
+        assert_eq!(one, many);
+        // and agrees with the time-based oscillator
+        let reference = signal::tri(440.0);
+        for (i, y) in one.iter().enumerate() {
+            assert!((*y as f64 - reference.at(i as f64 / SAMPLE_RATE as f64)).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn test_other_oscs_agree_with_signals() {
+        let check = |block: &mut dyn Block, signal: &dyn Signal| {
+            let mut out = vec![0.0; 10_000];
+            for chunk in out.chunks_mut(300) {
+                block.process(chunk);
+            }
+            for (i, y) in out.iter().enumerate() {
+                let x = signal.at(i as f64 / SAMPLE_RATE as f64);
+                assert!((*y as f64 - x).abs() < 1e-5, "{i}: {y} {x}");
+            }
+        };
+        check(&mut rabbit(110.0), &signal::rabbit().speed(2.0 * PI * 110.0));
+        check(&mut sinc_osc(110.0, 880.0), &signal::sinc_osc(110.0, 880.0));
+        check(&mut sinc_osc(2.0, 660.0), &signal::sinc_osc(2.0, 660.0));
+    }
+
+    #[test]
+    fn test_osc_phase_stays_wrapped() {
+        let mut osc = sine(1000.0);
+        let mut buf = [0.0; 4410];
+        for _ in 0..1000 {
+            osc.process(&mut buf);
+        }
+        // 100 s in, the phase is still within one cycle
+        assert!((0.0..TAU).contains(&osc.phase));
+        // a new frequency takes effect at the next block
+        osc.freq = 0.0;
+        osc.process(&mut buf);
+        assert!(buf.iter().all(|y| *y == buf[0]));
+    }
+
+    #[test]
+    fn test_limited_holds_ceiling() {
+        let mut loud = Limited::new(Stream::new(signal::sine(50.0).gain(4.0)), 0.8);
+        let out = run(&mut loud, 44_100, &[256]);
+        assert!(out.iter().all(|y| y.abs() <= 0.8));
+        assert!(out.iter().any(|y| y.abs() > 0.79));
+    }
+}
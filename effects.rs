@@ -0,0 +1,368 @@
+use crate::block::Block;
+use crate::SAMPLE_RATE;
+use std::f32::consts::FRAC_PI_2;
+
+// A stereo processing node. Effects work in place on matching left and right
+// blocks and keep their state (delay lines, filters) between calls.
+pub trait Effect {
+    fn process(&mut self, left: &mut [f32], right: &mut [f32]);
+}
+
+// A mono source followed by effects. The source is copied to both channels
+// before the first effect, so start with a `Pan` to place it.
+pub struct Chain {
+    source: Box<dyn Block>,
+    effects: Vec<Box<dyn Effect>>,
+}
+
+impl Chain {
+    pub fn new<B: Block + 'static>(source: B) -> Self {
+        Chain { source: Box::new(source), effects: Vec::new() }
+    }
+
+    pub fn with(mut self, effects: Vec<Box<dyn Effect>>) -> Self {
+        self.effects.extend(effects);
+        self
+    }
+
+    pub fn process(&mut self, left: &mut [f32], right: &mut [f32]) {
+        self.source.process(left);
+        right.copy_from_slice(left);
+        for effect in &mut self.effects {
+            effect.process(left, right);
+        }
+    }
+
+    pub fn render(&mut self, frames: usize) -> (Vec<f32>, Vec<f32>) {
+        let mut left = vec![0.0; frames];
+        let mut right = vec![0.0; frames];
+        for (l, r) in left.chunks_mut(1024).zip(right.chunks_mut(1024)) {
+            self.process(l, r);
+        }
+        (left, right)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PanLaw {
+    // gains sum to 1: a centred source drops 6 dB
+    Linear,
+    // sin/cos, powers sum to 1: a centred source drops 3 dB
+    ConstantPower,
+    // geometric mean of the two, 4.5 dB down in the centre
+    Compromise,
+}
+
+impl PanLaw {
+    // Left and right gains for `pan` from -1 (left) to 1 (right).
+    pub fn gains(self, pan: f32) -> (f32, f32) {
+        let p = (pan.clamp(-1.0, 1.0) + 1.0) / 2.0;
+        let linear = (1.0 - p, p);
+        let power = ((p * FRAC_PI_2).cos(), (p * FRAC_PI_2).sin());
+        match self {
+            PanLaw::Linear => linear,
+            PanLaw::ConstantPower => power,
+            PanLaw::Compromise => ((linear.0 * power.0).sqrt(), (linear.1 * power.1).sqrt()),
+        }
+    }
+}
+
+pub struct Pan {
+    left: f32,
+    right: f32,
+}
+
+impl Pan {
+    pub fn new(pan: f32, law: PanLaw) -> Self {
+        let (left, right) = law.gains(pan);
+        Pan { left, right }
+    }
+}
+
+impl Effect for Pan {
+    fn process(&mut self, left: &mut [f32], right: &mut [f32]) {
+        left.iter_mut().for_each(|l| *l *= self.left);
+        right.iter_mut().for_each(|r| *r *= self.right);
+    }
+}
+
+// Mid/side width: 0 folds to mono, 1 leaves the image alone, above 1 widens.
+pub struct Width(pub f32);
+
+impl Effect for Width {
+    fn process(&mut self, left: &mut [f32], right: &mut [f32]) {
+        for (l, r) in left.iter_mut().zip(right.iter_mut()) {
+            let mid = (*l + *r) / 2.0;
+            let side = (*l - *r) / 2.0 * self.0;
+            *l = mid + side;
+            *r = mid - side;
+        }
+    }
+}
+
+// Fixed delay of `buf.len()` samples.
+struct Line {
+    buf: Vec<f32>,
+    pos: usize,
+}
+
+impl Line {
+    fn new(len: usize) -> Self {
+        Line { buf: vec![0.0; len.max(1)], pos: 0 }
+    }
+
+    fn read(&self) -> f32 {
+        self.buf[self.pos]
+    }
+
+    fn write(&mut self, x: f32) {
+        self.buf[self.pos] = x;
+        self.pos = (self.pos + 1) % self.buf.len();
+    }
+}
+
+fn samples(secs: f32) -> usize {
+    (secs * SAMPLE_RATE as f32).round() as usize
+}
+
+// Echo with feedback. `mix` is the wet share. In ping-pong mode the input is
+// summed to mono and the echoes alternate between channels, left first.
+pub struct Delay {
+    left: Line,
+    right: Line,
+    feedback: f32,
+    mix: f32,
+    ping_pong: bool,
+}
+
+impl Delay {
+    pub fn new(secs: f32, feedback: f32, mix: f32, ping_pong: bool) -> Self {
+        let len = samples(secs);
+        Delay { left: Line::new(len), right: Line::new(len), feedback: feedback.clamp(0.0, 0.99), mix, ping_pong }
+    }
+}
+
+impl Effect for Delay {
+    fn process(&mut self, left: &mut [f32], right: &mut [f32]) {
+        for (l, r) in left.iter_mut().zip(right.iter_mut()) {
+            let (dl, dr) = (self.left.read(), self.right.read());
+            if self.ping_pong {
+                self.left.write((*l + *r) / 2.0 + self.feedback * dr);
+                self.right.write(self.feedback * dl);
+            } else {
+                self.left.write(*l + self.feedback * dl);
+                self.right.write(*r + self.feedback * dr);
+            }
+            *l = *l * (1.0 - self.mix) + dl * self.mix;
+            *r = *r * (1.0 - self.mix) + dr * self.mix;
+        }
+    }
+}
+
+// Schroeder allpass: flat magnitude, smears transients.
+struct Allpass {
+    line: Line,
+    gain: f32,
+}
+
+impl Allpass {
+    fn new(len: usize, gain: f32) -> Self {
+        Allpass { line: Line::new(len), gain }
+    }
+
+    fn process(&mut self, x: f32) -> f32 {
+        let delayed = self.line.read();
+        let v = x + self.gain * delayed;
+        self.line.write(v);
+        delayed - self.gain * v
+    }
+}
+
+// Mutually prime lengths at 44.1 kHz, 33 to 57 ms.
+const FDN_LENGTHS: [usize; 4] = [1447, 1811, 2137, 2503];
+
+// Four-line feedback delay network mixed by a Hadamard matrix, fed through
+// Schroeder allpasses (different per channel, so the tails decorrelate).
+// Each line's gain gives a 60 dB decay over `rt60` seconds; `damping` (0..1)
+// adds a one-pole low-pass in the loop so highs die sooner.
+pub struct Reverb {
+    lines: Vec<Line>,
+    gains: [f32; 4],
+    lows: [f32; 4],
+    damping: f32,
+    diffuse_left: [Allpass; 2],
+    diffuse_right: [Allpass; 2],
+    mix: f32,
+}
+
+impl Reverb {
+    pub fn new(rt60: f32, damping: f32, mix: f32) -> Self {
+        let gains = FDN_LENGTHS.map(|len| 10_f32.powf(-3.0 * len as f32 / (rt60.max(0.01) * SAMPLE_RATE as f32)));
+        Reverb {
+            lines: FDN_LENGTHS.iter().map(|&len| Line::new(len)).collect(),
+            gains,
+            lows: [0.0; 4],
+            damping: damping.clamp(0.0, 0.99),
+            diffuse_left: [Allpass::new(347, 0.7), Allpass::new(113, 0.7)],
+            diffuse_right: [Allpass::new(359, 0.7), Allpass::new(107, 0.7)],
+            mix,
+        }
+    }
+}
+
+impl Effect for Reverb {
+    fn process(&mut self, left: &mut [f32], right: &mut [f32]) {
+        for (l, r) in left.iter_mut().zip(right.iter_mut()) {
+            let il = self.diffuse_left.iter_mut().fold(*l, |x, ap| ap.process(x));
+            let ir = self.diffuse_right.iter_mut().fold(*r, |x, ap| ap.process(x));
+            let mut s = [0.0; 4];
+            for (i, line) in self.lines.iter().enumerate() {
+                self.lows[i] = line.read() * (1.0 - self.damping) + self.lows[i] * self.damping;
+                s[i] = self.lows[i];
+            }
+            // orthonormal 4x4 Hadamard
+            let h = [
+                (s[0] + s[1] + s[2] + s[3]) / 2.0,
+                (s[0] - s[1] + s[2] - s[3]) / 2.0,
+                (s[0] + s[1] - s[2] - s[3]) / 2.0,
+                (s[0] - s[1] - s[2] + s[3]) / 2.0,
+            ];
+            let input = [il, il, ir, ir];
+            for (i, line) in self.lines.iter_mut().enumerate() {
+                line.write(input[i] + self.gains[i] * h[i]);
+            }
+            let (wl, wr) = ((s[0] + s[2]) / 2.0, (s[1] + s[3]) / 2.0);
+            *l = *l * (1.0 - self.mix) + wl * self.mix;
+            *r = *r * (1.0 - self.mix) + wr * self.mix;
+        }
+    }
+}
+
+// Parses a chain like `pan:-0.3,delay:0.25:0.4:0.3:pingpong,reverb:1.8:0.25`:
+//   pan:P[:linear|power|compromise]     P from -1 to 1, constant power by default
+//   width:W
+//   delay:SECS:FEEDBACK:MIX[:pingpong]
+//   reverb:RT60:MIX[:DAMPING]
+pub fn parse_chain(spec: &str) -> Result<Vec<Box<dyn Effect>>, String> {
+    spec.split(',')
+        .filter(|s| !s.is_empty())
+        .map(|node| {
+            let fields: Vec<&str> = node.split(':').collect();
+            let num = |i: usize| {
+                fields.get(i).and_then(|f| f.parse::<f32>().ok()).ok_or_else(|| format!("bad or missing value {} in '{}'", i, node))
+            };
+            let effect: Box<dyn Effect> = match fields[0] {
+                "pan" => {
+                    let law = match fields.get(2).copied() {
+                        None | Some("power") => PanLaw::ConstantPower,
+                        Some("linear") => PanLaw::Linear,
+                        Some("compromise") => PanLaw::Compromise,
+                        Some(other) => return Err(format!("unknown pan law '{}'", other)),
+                    };
+                    Box::new(Pan::new(num(1)?, law))
+                }
+                "width" => Box::new(Width(num(1)?)),
+                "delay" => {
+                    let ping_pong = match fields.get(4).copied() {
+                        None => false,
+                        Some("pingpong") => true,
+                        Some(other) => return Err(format!("unknown delay mode '{}'", other)),
+                    };
+                    Box::new(Delay::new(num(1)?, num(2)?, num(3)?, ping_pong))
+                }
+                "reverb" => Box::new(Reverb::new(num(1)?, if fields.len() > 3 { num(3)? } else { 0.3 }, num(2)?)),
+                other => return Err(format!("unknown effect '{}', expected pan, width, delay or reverb", other)),
+            };
+            Ok(effect)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn impulse(frames: usize) -> (Vec<f32>, Vec<f32>) {
+        let mut left = vec![0.0; frames];
+        left[0] = 1.0;
+        (left.clone(), left)
+    }
+
+    #[test]
+    fn test_pan_laws() {
+        for pan in [-1.0, -0.4, 0.0, 0.7, 1.0] {
+            let (l, r) = PanLaw::ConstantPower.gains(pan);
+            assert!((l * l + r * r - 1.0).abs() < 1e-6);
+            let (l, r) = PanLaw::Linear.gains(pan);
+            assert!((l + r - 1.0).abs() < 1e-6);
+        }
+        let (l, r) = PanLaw::Compromise.gains(0.0);
+        assert!((20.0 * l.log10() + 4.5).abs() < 0.1 && l == r);
+        assert_eq!(PanLaw::ConstantPower.gains(-1.0), (1.0, 0.0));
+        assert!(PanLaw::ConstantPower.gains(1.0).0.abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_width() {
+        let (mut l, mut r) = (vec![1.0, 0.5], vec![0.0, 0.5]);
+        Width(1.0).process(&mut l, &mut r);
+        assert_eq!((l.clone(), r.clone()), (vec![1.0, 0.5], vec![0.0, 0.5]));
+        Width(0.0).process(&mut l, &mut r);
+        assert_eq!((l, r), (vec![0.5, 0.5], vec![0.5, 0.5]));
+    }
+
+    #[test]
+    fn test_delay_echoes() {
+        let t = samples(0.01);
+        let (mut l, mut r) = impulse(3 * t + 1);
+        Delay::new(0.01, 0.5, 0.5, false).process(&mut l, &mut r);
+        assert_eq!((l[0], l[t], l[2 * t], l[3 * t]), (0.5, 0.5, 0.25, 0.125));
+        assert_eq!(l, r);
+
+        let (mut l, mut r) = impulse(3 * t + 1);
+        Delay::new(0.01, 0.5, 0.5, true).process(&mut l, &mut r);
+        assert_eq!((l[t], r[t]), (0.5, 0.0));
+        assert_eq!((l[2 * t], r[2 * t]), (0.0, 0.25));
+        assert_eq!((l[3 * t], r[3 * t]), (0.125, 0.0));
+    }
+
+    #[test]
+    fn test_reverb_decays_by_rt60() {
+        let rt60 = 0.8;
+        let (mut l, mut r) = impulse(samples(1.2));
+        Reverb::new(rt60, 0.0, 1.0).process(&mut l, &mut r);
+        assert!(l.iter().chain(&r).all(|x| x.is_finite()));
+        assert_ne!(l, r);
+        let rms = |from: f32| {
+            let window = &l[samples(from)..samples(from + 0.1)];
+            (window.iter().map(|x| x * x).sum::<f32>() / window.len() as f32).sqrt()
+        };
+        let drop = 20.0 * (rms(0.1 + rt60) / rms(0.1)).log10();
+        assert!((-66.0..-54.0).contains(&drop), "{drop} dB");
+    }
+
+    #[test]
+    fn test_parse_reverb_fields() {
+        // RT60:MIX[:DAMPING], damping 0.3 when left out
+        let render = |mut effect: Box<dyn Effect>| {
+            let (mut l, mut r) = impulse(samples(0.5));
+            effect.process(&mut l, &mut r);
+            (l, r)
+        };
+        let parsed = |spec: &str| render(parse_chain(spec).unwrap().remove(0));
+        assert_eq!(parsed("reverb:1.5:0.2:0.7"), render(Box::new(Reverb::new(1.5, 0.7, 0.2))));
+        assert_eq!(parsed("reverb:1.5:0.2"), render(Box::new(Reverb::new(1.5, 0.3, 0.2))));
+        assert_ne!(parsed("reverb:1.5:0.2:0.7"), parsed("reverb:1.5:0.7:0.2"));
+    }
+
+    #[test]
+    fn test_chain_places_a_mono_source() {
+        let source = crate::block::sine(440.0);
+        let mut chain = Chain::new(source).with(parse_chain("pan:-0.5:linear,width:1").unwrap());
+        let (l, r) = chain.render(2000);
+        assert!(l.iter().zip(&r).all(|(l, r)| (l * 0.25 - r * 0.75).abs() < 1e-6));
+        assert!(parse_chain("pan:0,chorus:1").is_err());
+        assert!(parse_chain("delay:0.1:0.5").is_err());
+        assert_eq!(parse_chain("reverb:2:0.3,delay:0.2:0.3:0.2:pingpong").unwrap().len(), 2);
+    }
+}
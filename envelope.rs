@@ -0,0 +1,188 @@
+use crate::signal::Signal;
+
+// How a segment moves from its start level to its target, as a map of 0..=1
+// onto 0..=1.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Curve {
+    Linear,
+    // `1 - e^(-k x)` normalised: positive k rushes towards the target and
+    // settles (like an RC circuit), negative k starts slowly. 0 is linear.
+    Exp(f64),
+    // smoothstep, flat at both ends
+    Smooth,
+}
+
+impl Curve {
+    pub fn shape(self, x: f64) -> f64 {
+        let x = x.clamp(0.0, 1.0);
+        match self {
+            Curve::Exp(k) if k.abs() > 1e-9 => (1.0 - (-k * x).exp()) / (1.0 - (-k).exp()),
+            Curve::Linear | Curve::Exp(_) => x,
+            Curve::Smooth => x * x * (3.0 - 2.0 * x),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Segment {
+    pub duration: f64,
+    pub level: f64,
+    pub curve: Curve,
+}
+
+impl Segment {
+    pub fn new(duration: f64, level: f64, curve: Curve) -> Segment {
+        Segment { duration: duration.max(0.0), level, curve }
+    }
+}
+
+// Runs from `start` through `segments`, each of which ends at its own level.
+// Without a release the envelope is one-shot: it plays out from the trigger
+// and ignores when the gate closes. With one it holds the last level while
+// the gate is open and runs the release from wherever it is when it closes.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Envelope {
+    pub start: f64,
+    pub segments: Vec<Segment>,
+    pub release: Option<Vec<Segment>>,
+}
+
+// What a new trigger does while the envelope is still moving.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Retrigger {
+    // jump back to `start`
+    Reset,
+    // start the segments again from the current level, without a click
+    Legato,
+}
+
+impl Envelope {
+    pub fn breakpoints(start: f64, segments: Vec<Segment>) -> Envelope {
+        Envelope { start, segments, release: None }
+    }
+
+    pub fn adsr(attack: f64, decay: f64, sustain: f64, release: f64) -> Envelope {
+        Envelope {
+            start: 0.0,
+            segments: vec![Segment::new(attack, 1.0, Curve::Linear), Segment::new(decay, sustain, Curve::Exp(5.0))],
+            release: Some(vec![Segment::new(release, 0.0, Curve::Exp(5.0))]),
+        }
+    }
+
+    // Level `t` seconds after a trigger that found the envelope at `from`,
+    // with the gate closing after `held` seconds.
+    pub fn level(&self, from: f64, t: f64, held: f64) -> f64 {
+        match &self.release {
+            Some(release) if t >= held => run(release, run(&self.segments, from, held), t - held),
+            _ => run(&self.segments, from, t),
+        }
+    }
+
+    // Plays the envelope once per `(on, off)` gate, in seconds.
+    pub fn trigger(self, gates: &[(f64, f64)], mode: Retrigger) -> Triggered {
+        let mut gates = gates.to_vec();
+        gates.sort_by(|a, b| a.0.total_cmp(&b.0));
+        let mut from = Vec::with_capacity(gates.len());
+        for (k, &(on, _)) in gates.iter().enumerate() {
+            let level = match (mode, k) {
+                (Retrigger::Legato, 1..) => {
+                    let (last_on, last_off) = gates[k - 1];
+                    self.level(from[k - 1], on - last_on, last_off - last_on)
+                }
+                _ => self.start,
+            };
+            from.push(level);
+        }
+        Triggered { envelope: self, gates, from }
+    }
+}
+
+fn run(segments: &[Segment], from: f64, mut t: f64) -> f64 {
+    let mut level = from;
+    for seg in segments {
+        if t < seg.duration {
+            return level + (seg.level - level) * seg.curve.shape(t / seg.duration);
+        }
+        t -= seg.duration;
+        level = seg.level;
+    }
+    level
+}
+
+// An envelope bound to its gates; `start` before the first one.
+pub struct Triggered {
+    envelope: Envelope,
+    gates: Vec<(f64, f64)>,
+    // level each gate's trigger found the envelope at
+    from: Vec<f64>,
+}
+
+impl Signal for Triggered {
+    fn at(&self, t: f64) -> f64 {
+        match self.gates.partition_point(|g| g.0 <= t) {
+            0 => self.envelope.start,
+            k => {
+                let (on, off) = self.gates[k - 1];
+                self.envelope.level(self.from[k - 1], t - on, off - on)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-9
+    }
+
+    #[test]
+    fn test_adsr_stages() {
+        let adsr = Envelope::adsr(0.1, 0.2, 0.5, 0.3).trigger(&[(1.0, 2.0)], Retrigger::Reset);
+        assert_eq!(adsr.at(0.5), 0.0);
+        assert!(close(adsr.at(1.05), 0.5));
+        assert!(close(adsr.at(1.1), 1.0));
+        assert!(adsr.at(1.2) < 1.0 && adsr.at(1.2) > 0.5);
+        assert!(close(adsr.at(1.3), 0.5));
+        assert!(close(adsr.at(1.9), 0.5));
+        assert!(adsr.at(2.1) < 0.5 && adsr.at(2.1) > 0.0);
+        assert!(close(adsr.at(2.3), 0.0));
+        assert!(close(adsr.at(5.0), 0.0));
+    }
+
+    #[test]
+    fn test_release_starts_from_current_level() {
+        // gate closes halfway up the attack
+        let adsr = Envelope::adsr(0.2, 0.2, 0.5, 0.1);
+        assert!(close(adsr.level(0.0, 0.1, 0.1), 0.5));
+        let just_after = adsr.level(0.0, 0.1 + 1e-6, 0.1);
+        assert!(just_after < 0.5 && just_after > 0.49);
+        assert!(close(adsr.level(0.0, 0.2, 0.1), 0.0));
+    }
+
+    #[test]
+    fn test_one_shot_ignores_gate_off() {
+        let shape = Envelope::breakpoints(1.0, vec![Segment::new(1.0, 0.0, Curve::Smooth), Segment::new(1.0, 0.5, Curve::Exp(-3.0))]);
+        let hit = shape.trigger(&[(0.0, 0.1)], Retrigger::Reset);
+        assert!(close(hit.at(0.5), 0.5));
+        assert!(close(hit.at(1.0), 0.0));
+        assert!(hit.at(1.5) < 0.25);
+        assert!(close(hit.at(3.0), 0.5));
+    }
+
+    #[test]
+    fn test_retrigger_modes() {
+        let gates = [(0.0, 0.05), (0.1, 1.0)];
+        let reset = Envelope::adsr(0.2, 0.1, 0.5, 0.1).trigger(&gates, Retrigger::Reset);
+        let legato = Envelope::adsr(0.2, 0.1, 0.5, 0.1).trigger(&gates, Retrigger::Legato);
+        // the first note is released from 0.25 and is halfway through its release when retriggered
+        let level = 0.25 * (1.0 - Curve::Exp(5.0).shape(0.5));
+        assert!(close(legato.at(0.1 - 1e-12), level));
+        assert!(close(legato.at(0.1), level));
+        assert_eq!(reset.at(0.1), 0.0);
+        // both reach the peak at the end of the attack
+        assert!(close(legato.at(0.3), 1.0));
+        assert!(close(reset.at(0.3), 1.0));
+    }
+}
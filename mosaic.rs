@@ -1,7 +1,68 @@
-use img::{GenericImage, GenericImageView, ImageBuffer, RgbImage};
-use std::f64::consts::PI;
-use rand::Rng;
-use std::env;
+use img::codecs::gif::{GifEncoder, Repeat};
+use img::{Delay, DynamicImage, Frame, ImageBuffer, ImageError, ImageFormat, Rgb, Rgb32FImage, RgbImage};
+use std::f64::consts::{FRAC_2_PI, PI};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use serde::Deserialize;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::thread;
+use tiff::encoder::{colortype, TiffEncoder};
+use tiff::TiffError;
+
+#[derive(Debug)]
+pub enum MosaicError {
+    InvalidParams(String),
+    Palette(String),
+    Io(io::Error),
+    Image(ImageError),
+    Tiff(TiffError),
+}
+
+impl fmt::Display for MosaicError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MosaicError::InvalidParams(msg) => write!(f, "invalid parameters: {}", msg),
+            MosaicError::Palette(msg) => write!(f, "palette error: {}", msg),
+            MosaicError::Io(e) => write!(f, "io error: {}", e),
+            MosaicError::Image(e) => write!(f, "image error: {}", e),
+            MosaicError::Tiff(e) => write!(f, "tiff error: {}", e),
+        }
+    }
+}
+
+impl Error for MosaicError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            MosaicError::Io(e) => Some(e),
+            MosaicError::Image(e) => Some(e),
+            MosaicError::Tiff(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for MosaicError {
+    fn from(e: io::Error) -> Self {
+        MosaicError::Io(e)
+    }
+}
+
+impl From<ImageError> for MosaicError {
+    fn from(e: ImageError) -> Self {
+        MosaicError::Image(e)
+    }
+}
+
+impl From<TiffError> for MosaicError {
+    fn from(e: TiffError) -> Self {
+        MosaicError::Tiff(e)
+    }
+}
 
 fn lattice_centers(x: f64, y: f64, sz: f64) -> (f64, f64) {
     let h = sz / 2.0;
@@ -12,15 +73,24 @@ fn lattice_centers(x: f64, y: f64, sz: f64) -> (f64, f64) {
     (b, d)
 }
 
-fn hex_lattice_centers(x: f64, y: f64, sz: f64) -> (f64, f64) {
+// Fractional cube coordinates (q + r + s = 0) of a point on a flat-top hex grid.
+fn cube_coords(x: f64, y: f64, sz: f64) -> (f64, f64, f64) {
     let sqrt3 = 3.0f64.sqrt();
-    
-    let hex_width = sz * 2.0;
-    let hex_height = sz * sqrt3;
-    
     let q = (2.0/3.0 * x) / sz;
     let r = (-1.0/3.0 * x + sqrt3/3.0 * y) / sz;
     let s = -q - r;  // cubic coordinates: q + r + s = 0
+    (q, r, s)
+}
+
+fn cube_to_pixel(q: f64, r: f64, sz: f64) -> (f64, f64) {
+    let sqrt3 = 3.0f64.sqrt();
+    let center_x = sz * 3.0/2.0 * q;
+    let center_y = sz * sqrt3 * (r + q/2.0);
+    (center_x, center_y)
+}
+
+fn hex_lattice_centers(x: f64, y: f64, sz: f64) -> (f64, f64) {
+    let (q, r, s) = cube_coords(x, y, sz);
     
     let q_round = q.round();
     let r_round = r.round();
@@ -38,19 +108,227 @@ fn hex_lattice_centers(x: f64, y: f64, sz: f64) -> (f64, f64) {
         (q_round, r_round)
     };
     
-    let center_x = sz * 3.0/2.0 * q_final;
-    let center_y = sz * sqrt3 * (r_final + q_final/2.0);
-    
-    (center_x, center_y)
+    cube_to_pixel(q_final, r_final, sz)
 }
 
+// The hex centers are the vertices of a triangular tiling. Flooring the cube
+// coordinates picks a unit cube whose corners on the q + r + s = 0 plane form
+// the containing triangle: one "+1" corner per axis when the floors sum to -1,
+// two when they sum to -2. Either way the centroid is floor + (-sum) / 3.
+fn tri_lattice_centers(x: f64, y: f64, sz: f64) -> (f64, f64) {
+    let (q, r, s) = cube_coords(x, y, sz);
 
+    let (qf, rf, sf) = (q.floor(), r.floor(), s.floor());
+    let offset = -(qf + rf + sf) / 3.0;
+
+    cube_to_pixel(qf + offset, rf + offset, sz)
+}
+
+// Anything that can snap a point to the center of the cell containing it.
+pub trait Tiling: Sync {
+    fn centers(&self, x: f64, y: f64, sz: f64) -> (f64, f64);
+}
 
-fn osc(a: f64) -> f64 {
+fn splitmix64(mut z: u64) -> u64 {
+    z = z.wrapping_add(0x9e3779b97f4a7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+// Voronoi cells around one jittered feature point per `sz` grid square. The
+// points stay within the middle half of their square, which guarantees the
+// nearest one is always among the 3 x 3 neighbouring squares.
+#[derive(Debug, Clone, Copy)]
+pub struct Voronoi {
+    pub seed: u64,
+}
+
+impl Voronoi {
+    fn feature(&self, i: i64, j: i64) -> (f64, f64) {
+        let h = splitmix64(self.seed ^ splitmix64((i as u64).wrapping_mul(0x8cb92ba72f3d8dd7) ^ j as u64));
+        let u = (h >> 11) as f64 / (1u64 << 53) as f64;
+        let v = (splitmix64(h) >> 11) as f64 / (1u64 << 53) as f64;
+        (i as f64 + 0.25 + 0.5 * u, j as f64 + 0.25 + 0.5 * v)
+    }
+}
+
+impl Tiling for Voronoi {
+    fn centers(&self, x: f64, y: f64, sz: f64) -> (f64, f64) {
+        let (gx, gy) = (x / sz, y / sz);
+        let (i0, j0) = (gx.floor() as i64, gy.floor() as i64);
+        let mut best = (f64::INFINITY, (0.0, 0.0));
+        for j in j0 - 1..=j0 + 1 {
+            for i in i0 - 1..=i0 + 1 {
+                let (fx, fy) = self.feature(i, j);
+                let d = (fx - gx) * (fx - gx) + (fy - gy) * (fy - gy);
+                if d < best.0 {
+                    best = (d, (fx, fy));
+                }
+            }
+        }
+        (best.1 .0 * sz, best.1 .1 * sz)
+    }
+}
+
+// Penrose rhombus tiling built with de Bruijn's pentagrid. Five families of
+// parallel lines with normals `e_k` and offsets `gamma_k` (summing to zero)
+// cut the grid plane into faces; the face with indices `K_k` maps to the tile
+// vertex `sum K_k e_k`, and every crossing of two lines becomes a rhombus.
+#[derive(Debug, Clone, Copy)]
+pub struct Penrose {
+    pub gamma: [f64; 5],
+}
+
+impl Default for Penrose {
+    fn default() -> Self {
+        Self { gamma: [0.1, 0.2, -0.3, 0.15, -0.15] }
+    }
+}
+
+impl Penrose {
+    fn normals() -> [(f64, f64); 5] {
+        [0, 1, 2, 3, 4].map(|k| {
+            let a = 2.0 * PI * k as f64 / 5.0;
+            (a.cos(), a.sin())
+        })
+    }
+
+    // The rhombus for the crossing of line `nr` of family `r` and line `ns` of
+    // family `s`, as (lowest vertex, edge r, edge s).
+    fn rhombus(&self, e: &[(f64, f64); 5], r: usize, s: usize, nr: f64, ns: f64) -> (f64, f64) {
+        let (a, b) = (e[r], e[s]);
+        let (cr, cs) = (nr - self.gamma[r], ns - self.gamma[s]);
+        let det = a.0 * b.1 - a.1 * b.0;
+        let q = ((cr * b.1 - cs * a.1) / det, (a.0 * cs - b.0 * cr) / det);
+        let mut v = (0.0, 0.0);
+        for (k, ek) in e.iter().enumerate() {
+            let n = if k == r {
+                nr - 1.0
+            } else if k == s {
+                ns - 1.0
+            } else {
+                (q.0 * ek.0 + q.1 * ek.1 + self.gamma[k]).floor()
+            };
+            v = (v.0 + n * ek.0, v.1 + n * ek.1);
+        }
+        v
+    }
+
+    // The rhombus containing `(x, y)` in unit-edge coordinates, as its lowest
+    // vertex and the two families `(r, s)` whose edges span it.
+    pub fn tile(&self, x: f64, y: f64) -> Option<((f64, f64), usize, usize)> {
+        let e = Self::normals();
+        // Two lines either side is enough in exact arithmetic; the wider passes
+        // only matter when rounding far from the origin pushes the crossing out.
+        (2..=4).find_map(|reach| {
+            self.candidates(x, y, reach).find(|&(v, r, s)| {
+                let (a, b) = (e[r], e[s]);
+                let det = a.0 * b.1 - a.1 * b.0;
+                let d = (x - v.0, y - v.1);
+                let u = (d.0 * b.1 - d.1 * b.0) / det;
+                let w = (a.0 * d.1 - a.1 * d.0) / det;
+                (0.0..=1.0).contains(&u) && (0.0..=1.0).contains(&w)
+            })
+        })
+    }
+
+    // The rhombi for the crossings within `reach` lines of the ones nearest
+    // `(x, y)`. A face vertex sum K_k e_k is 5/2 times its grid point plus a
+    // bounded offset, so the crossing we want lies near p * 2/5.
+    fn candidates(&self, x: f64, y: f64, reach: i32) -> impl Iterator<Item = ((f64, f64), usize, usize)> + '_ {
+        let e = Self::normals();
+        let g = (x * 0.4, y * 0.4);
+        let steps = move || (-reach..=reach).map(f64::from);
+        (0..5).flat_map(move |r| (r + 1..5).map(move |s| (r, s))).flat_map(move |(r, s)| {
+            let cr = (g.0 * e[r].0 + g.1 * e[r].1 + self.gamma[r]).round();
+            let cs = (g.0 * e[s].0 + g.1 * e[s].1 + self.gamma[s]).round();
+            steps().flat_map(move |i| steps().map(move |j| (self.rhombus(&e, r, s, cr + i, cs + j), r, s)))
+        })
+    }
+
+    fn center(v: (f64, f64), r: usize, s: usize) -> (f64, f64) {
+        let e = Self::normals();
+        (v.0 + (e[r].0 + e[s].0) / 2.0, v.1 + (e[r].1 + e[s].1) / 2.0)
+    }
+}
+
+impl Tiling for Penrose {
+    fn centers(&self, x: f64, y: f64, sz: f64) -> (f64, f64) {
+        let (x, y) = (x / sz, y / sz);
+        let center = match self.tile(x, y) {
+            Some((v, r, s)) => Self::center(v, r, s),
+            // precision has run out this far away; the nearest center will do
+            None => self
+                .candidates(x, y, 2)
+                .map(|(v, r, s)| Self::center(v, r, s))
+                .min_by(|a, b| {
+                    let d = |c: &(f64, f64)| (c.0 - x).powi(2) + (c.1 - y).powi(2);
+                    d(a).total_cmp(&d(b))
+                })
+                .unwrap_or((x, y)),
+        };
+        (center.0 * sz, center.1 * sz)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lattice {
+    Square,
+    Hex,
+    Tri,
+    Voronoi,
+    Penrose,
+}
+
+impl Tiling for Lattice {
+    fn centers(&self, x: f64, y: f64, sz: f64) -> (f64, f64) {
+        match self {
+            Lattice::Square => lattice_centers(x, y, sz),
+            Lattice::Hex => hex_lattice_centers(x, y, sz),
+            Lattice::Tri => tri_lattice_centers(x, y, sz),
+            // a bare lattice has no seed; `Params::tiling` uses the render's
+            Lattice::Voronoi => Voronoi { seed: 0 }.centers(x, y, sz),
+            Lattice::Penrose => Penrose::default().centers(x, y, sz),
+        }
+    }
+}
+
+impl Lattice {
+    // Short tag used in output file names, matching the shipped renders.
+    pub fn tag(self) -> &'static str {
+        match self {
+            Lattice::Square => "sq",
+            Lattice::Hex => "hex",
+            Lattice::Tri => "tri",
+            Lattice::Voronoi => "vor",
+            Lattice::Penrose => "pen",
+        }
+    }
+}
+
+impl FromStr for Lattice {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "square" | "sq" => Ok(Lattice::Square),
+            "hex" => Ok(Lattice::Hex),
+            "tri" | "triangle" => Ok(Lattice::Tri),
+            "voronoi" | "vor" => Ok(Lattice::Voronoi),
+            "penrose" | "pen" => Ok(Lattice::Penrose),
+            other => Err(format!("unknown lattice '{other}', expected square, hex, tri, voronoi or penrose")),
+        }
+    }
+}
+
+
+
+pub fn osc(a: f64) -> f64 {
     a.sin() / a
 }
 
-fn osc2(a: f64, n: usize) -> f64 {
+pub fn osc2(a: f64, n: usize) -> f64 {
     let mut v = 0_f64;
     let x = a / n as f64;
     for i in 1..=n {
@@ -59,40 +337,998 @@ fn osc2(a: f64, n: usize) -> f64 {
     v / n as f64
 }
 
-fn main() {
-  let args: Vec<String> = env::args().collect();
-    
-    let base = if args.len() > 1 {
-        args[1].parse::<f64>().unwrap()
+// Bessel J0 and J1, rational approximations from Numerical Recipes (bessj0/bessj1).
+fn bessel_j0(x: f64) -> f64 {
+    let ax = x.abs();
+    if ax < 8.0 {
+        let y = x * x;
+        let a = 57568490574.0 + y * (-13362590354.0 + y * (651619640.7
+            + y * (-11214424.18 + y * (77392.33017 + y * (-184.9052456)))));
+        let b = 57568490411.0 + y * (1029532985.0 + y * (9494680.718
+            + y * (59272.64853 + y * (267.8532712 + y))));
+        a / b
     } else {
-        17.0
-    };
-    
-    let multiplier = if args.len() > 2 {
-        args[2].parse::<f64>().unwrap()
+        let z = 8.0 / ax;
+        let y = z * z;
+        let xx = ax - 0.785398164;
+        let a = 1.0 + y * (-0.1098628627e-2 + y * (0.2734510407e-4
+            + y * (-0.2073370639e-5 + y * 0.2093887211e-6)));
+        let b = -0.1562499995e-1 + y * (0.1430488765e-3
+            + y * (-0.6911147651e-5 + y * (0.7621095161e-6 - y * 0.934935152e-7)));
+        (FRAC_2_PI / ax).sqrt() * (xx.cos() * a - z * xx.sin() * b)
+    }
+}
+
+fn bessel_j1(x: f64) -> f64 {
+    let ax = x.abs();
+    if ax < 8.0 {
+        let y = x * x;
+        let a = x * (72362614232.0 + y * (-7895059235.0 + y * (242396853.1
+            + y * (-2972611.439 + y * (15704.48260 + y * (-30.16036606))))));
+        let b = 144725228442.0 + y * (2300535178.0 + y * (18583304.74
+            + y * (99447.43394 + y * (376.9991397 + y))));
+        a / b
     } else {
-        5.0
-    };
+        let z = 8.0 / ax;
+        let y = z * z;
+        let xx = ax - 2.356194491;
+        let a = 1.0 + y * (0.183105e-2 + y * (-0.3516396496e-4
+            + y * (0.2457520174e-5 + y * (-0.240337019e-6))));
+        let b = 0.04687499995 + y * (-0.2002690873e-3
+            + y * (0.8449199096e-5 + y * (-0.88228987e-6 + y * 0.105787412e-6)));
+        let v = (FRAC_2_PI / ax).sqrt() * (xx.cos() * a - z * xx.sin() * b);
+        if x < 0.0 { -v } else { v }
+    }
+}
+
+// A scalar field over the plane, sampled at lattice-snapped points. Values are
+// expected roughly in -1..1; the palette maps them to 0..1 before mixing.
+pub trait Kernel: Sync {
+    fn eval(&self, x: f64, y: f64) -> f64;
+
+    fn add<K: Kernel>(self, other: K) -> Sum<Self, K>
+    where
+        Self: Sized,
+    {
+        Sum(self, other)
+    }
+
+    fn mul<K: Kernel>(self, other: K) -> Product<Self, K>
+    where
+        Self: Sized,
+    {
+        Product(self, other)
+    }
+
+    // Evaluates the kernel at `warp(x, y)` instead of `(x, y)`.
+    fn warp<W>(self, warp: W) -> Warp<Self, W>
+    where
+        Self: Sized,
+        W: Fn(f64, f64) -> (f64, f64) + Sync,
+    {
+        Warp { kernel: self, warp }
+    }
+}
+
+impl<F: Fn(f64, f64) -> f64 + Sync> Kernel for F {
+    fn eval(&self, x: f64, y: f64) -> f64 {
+        self(x, y)
+    }
+}
+
+impl Kernel for Box<dyn Kernel> {
+    fn eval(&self, x: f64, y: f64) -> f64 {
+        (**self).eval(x, y)
+    }
+}
+
+pub struct Sum<A, B>(A, B);
+
+impl<A: Kernel, B: Kernel> Kernel for Sum<A, B> {
+    fn eval(&self, x: f64, y: f64) -> f64 {
+        self.0.eval(x, y) + self.1.eval(x, y)
+    }
+}
+
+pub struct Product<A, B>(A, B);
+
+impl<A: Kernel, B: Kernel> Kernel for Product<A, B> {
+    fn eval(&self, x: f64, y: f64) -> f64 {
+        self.0.eval(x, y) * self.1.eval(x, y)
+    }
+}
+
+pub struct Warp<K, W> {
+    kernel: K,
+    warp: W,
+}
+
+impl<K: Kernel, W: Fn(f64, f64) -> (f64, f64) + Sync> Kernel for Warp<K, W> {
+    fn eval(&self, x: f64, y: f64) -> f64 {
+        let (wx, wy) = (self.warp)(x, y);
+        self.kernel.eval(wx, wy)
+    }
+}
+
+// Radial profiles of the squared distance, all fed the same `r² · π / scale + phase`
+// argument so they produce the same family of moiré rings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Profile {
+    Osc,
+    Osc2,
+    Bessel,
+    Airy,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Radial {
+    pub profile: Profile,
+    // `n` for osc2; a multiplier on the argument for the other profiles.
+    pub harmonic: usize,
+    pub scale: f64,
+    pub phase: f64,
+}
+
+impl Kernel for Radial {
+    fn eval(&self, x: f64, y: f64) -> f64 {
+        let a = (x*x + y*y) * PI / self.scale + self.phase;
+        let n = self.harmonic as f64;
+        match self.profile {
+            Profile::Osc2 => osc2(a, self.harmonic),
+            Profile::Osc if a * n == 0.0 => 1.0,
+            Profile::Osc => osc(a * n),
+            Profile::Bessel => bessel_j0(a * n),
+            Profile::Airy => {
+                let an = a * n;
+                let amplitude = if an == 0.0 { 1.0 } else { 2.0 * bessel_j1(an) / an };
+                amplitude * amplitude * 2.0 - 1.0
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ChannelWeights {
+    pub red: Vec<f64>,
+    pub green: Vec<f64>,
+    pub blue: Vec<f64>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GradientStop {
+    pub at: f64,
+    pub color: [u8; 3],
+}
+
+// Describes how the radial field becomes a colour: which kernel profile and
+// harmonics are sampled, how each channel mixes them, a gamma curve and an
+// optional gradient map applied to the mixed luminance.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Palette {
+    pub kernel: Profile,
+    pub harmonics: Vec<usize>,
+    pub weights: ChannelWeights,
+    pub gamma: f64,
+    pub gradient: Vec<GradientStop>,
+}
+
+impl Default for ChannelWeights {
+    fn default() -> Self {
+        Self {
+            red: vec![1.0, 0.0, 2.0],
+            green: vec![1.0, 1.0, 1.0],
+            blue: vec![1.0, 2.0, 0.0],
+        }
+    }
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Self {
+            kernel: Profile::Osc2,
+            harmonics: vec![1, 2, 3],
+            weights: ChannelWeights::default(),
+            gamma: 1.0,
+            gradient: vec![],
+        }
+    }
+}
+
+impl Palette {
+    // Loads a palette from a `.toml` or `.json` file, chosen by extension.
+    pub fn load(path: &Path) -> Result<Self, MosaicError> {
+        let text = fs::read_to_string(path)?;
+        let mut palette: Palette = match path.extension().and_then(|e| e.to_str()) {
+            Some("toml") => toml::from_str(&text).map_err(|e| MosaicError::Palette(e.to_string()))?,
+            Some("json") => serde_json::from_str(&text).map_err(|e| MosaicError::Palette(e.to_string()))?,
+            _ => return Err(MosaicError::Palette(format!("{} must be .toml or .json", path.display()))),
+        };
+        palette.gradient.sort_by(|a, b| a.at.total_cmp(&b.at));
+        palette.check()?;
+        Ok(palette)
+    }
+
+    pub fn check(&self) -> Result<(), MosaicError> {
+        let invalid = |msg: String| Err(MosaicError::Palette(msg));
+        let n = self.harmonics.len();
+        if n == 0 || self.harmonics.contains(&0) {
+            return invalid("harmonics must be a non-empty list of positive integers".into());
+        }
+        for (name, w) in [("red", &self.weights.red), ("green", &self.weights.green), ("blue", &self.weights.blue)] {
+            if w.len() != n {
+                return invalid(format!("{name} weights have {} entries, expected {n}", w.len()));
+            }
+        }
+        if self.gamma.is_nan() || self.gamma <= 0.0 {
+            return invalid(format!("gamma must be positive, got {}", self.gamma));
+        }
+        if self.gradient.windows(2).any(|w| w[0].at > w[1].at) {
+            return invalid("gradient stops must be sorted by `at`".into());
+        }
+        Ok(())
+    }
+
+    // One radial kernel per harmonic, the inputs `shade` mixes by default.
+    pub fn kernels(&self, pattern: &Pattern) -> Vec<Box<dyn Kernel>> {
+        self.harmonics.iter()
+            .map(|&harmonic| {
+                let radial = Radial { profile: self.kernel, harmonic, scale: pattern.scale, phase: pattern.phase };
+                Box::new(radial) as Box<dyn Kernel>
+            })
+            .collect()
+    }
+
+    // Maps kernel values (one per weight column) to an RGB triple in 0..1.
+    pub fn shade(&self, values: &[f64]) -> [f64; 3] {
+        let h: Vec<f64> = values.iter().map(|v| (v + 1.0) / 2.0).collect();
+        let mix = |w: &[f64]| {
+            let total: f64 = w.iter().sum();
+            if total == 0.0 { return 0.0; }
+            w.iter().zip(&h).map(|(w, v)| w * v).sum::<f64>() / total
+        };
+        let rgb = [mix(&self.weights.red), mix(&self.weights.green), mix(&self.weights.blue)];
+        let rgb = if self.gradient.is_empty() {
+            rgb
+        } else {
+            self.gradient_map((rgb[0] + rgb[1] + rgb[2]) / 3.0)
+        };
+        rgb.map(|c| c.clamp(0.0, 1.0).powf(1.0 / self.gamma))
+    }
+
+    // Phase offset after which every osc2 harmonic is back where it started:
+    // `osc2(a, n)` repeats every 2πn in `a`, so the mix repeats at 2π·lcm(n).
+    // The other profiles decay with `a` and never repeat, so they have none.
+    pub fn loop_period(&self) -> Option<f64> {
+        if self.kernel != Profile::Osc2 {
+            return None;
+        }
+        let gcd = |mut a: usize, mut b: usize| {
+            while b != 0 { (a, b) = (b, a % b); }
+            a
+        };
+        let lcm = self.harmonics.iter().fold(1, |acc, &n| acc / gcd(acc, n) * n);
+        Some(2.0 * PI * lcm as f64)
+    }
+
+    fn gradient_map(&self, t: f64) -> [f64; 3] {
+        let stops = &self.gradient;
+        let to_unit = |c: [u8; 3]| c.map(|v| v as f64 / 255.0);
+        let first = &stops[0];
+        let last = &stops[stops.len() - 1];
+        if t <= first.at { return to_unit(first.color); }
+        if t >= last.at { return to_unit(last.color); }
+        let k = stops.iter().position(|s| s.at > t).unwrap();
+        let (a, b) = (&stops[k - 1], &stops[k]);
+        let f = (t - a.at) / (b.at - a.at);
+        let (ca, cb) = (to_unit(a.color), to_unit(b.color));
+        [0, 1, 2].map(|i| ca[i] + (cb[i] - ca[i]) * f)
+    }
+}
+
+// Reconstruction filter used to weight the samples of one pixel. The samples
+// are stratified over the pixel itself, one per cell of a `samples` x
+// `samples` grid, and weighted by their offset from the pixel center.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Filter {
+    Box,
+    Gaussian,
+    Lanczos,
+}
+
+impl Filter {
+    // Separable weight of a sample `(dx, dy)` pixels away from the pixel center.
+    fn weight(self, dx: f64, dy: f64) -> f64 {
+        let along = |d: f64| match self {
+            Filter::Box => 1.0,
+            // sigma = 0.5 px
+            Filter::Gaussian => (-2.0 * d * d).exp(),
+            Filter::Lanczos => {
+                let sinc = |t: f64| if t == 0.0 { 1.0 } else { (PI * t).sin() / (PI * t) };
+                sinc(d) * sinc(d / 2.0)
+            }
+        };
+        along(dx) * along(dy)
+    }
+}
+
+impl FromStr for Filter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "box" => Ok(Filter::Box),
+            "gaussian" | "gauss" => Ok(Filter::Gaussian),
+            "lanczos" => Ok(Filter::Lanczos),
+            other => Err(format!("unknown filter '{other}', expected box, gaussian or lanczos")),
+        }
+    }
+}
+
+// Which part of the plane ends up on the canvas. `zoom` is pixels per lattice
+// unit, so zoom 1 with a zero center reproduces the original 1024 x 1024 view.
+// Each pixel takes `samples` x `samples` jittered samples combined by `filter`.
+#[derive(Debug, Clone, Copy)]
+pub struct View {
+    pub width: u32,
+    pub height: u32,
+    pub center: (f64, f64),
+    pub zoom: f64,
+    pub samples: u32,
+    pub filter: Filter,
+}
+
+impl View {
+    pub fn new(width: u32, height: u32) -> Self {
+        Self { width, height, center: (0.0, 0.0), zoom: 1.0, samples: 1, filter: Filter::Box }
+    }
+
+    fn to_plane(self, x: f64, y: f64) -> (f64, f64) {
+        let ox = (x - self.width as f64 / 2.0) / self.zoom + self.center.0;
+        let oy = (y - self.height as f64 / 2.0) / self.zoom + self.center.1;
+        (ox, oy)
+    }
+}
+
+// What is drawn: the lattice cells are snapped to, the radial scale and a
+// phase offset added to the `osc2` argument.
+#[derive(Debug, Clone, Copy)]
+pub struct Pattern {
+    pub lattice: Lattice,
+    pub scale: f64,
+    pub phase: f64,
+}
 
-    let pattern_scale = base / multiplier;
-    println!("Using pattern scale: {}", pattern_scale);
-
-    let mut rng = rand::thread_rng();
-    let mut img = ImageBuffer::from_fn(1024, 1024, |x, y| {
-        let r1: f64 = rng.gen();
-        let r2: f64 = rng.gen();
-        let (x2, y2) = lattice_centers(x as f64 - 512.0 + r1, y as f64 - 512.0 + r2, 3.0);
-        let sqdist2 = x2*x2 + y2*y2;
-        let v = (osc2(sqdist2 * PI / pattern_scale, 1) * 1.0 + 1.0) / 2.0;
-        let w = (osc2(sqdist2 * PI / pattern_scale, 2) * 1.0 + 1.0) / 2.0;
-        let u = (osc2(sqdist2 * PI / pattern_scale, 3) * 1.0 + 1.0) / 2.0;
-        let a = ( v * 1.0 + w * 0.0 + u * 2.0 ) / ( 1.0 + 2.0 + 0.0 );
-        let b = ( v * 1.0 + w * 1.0 + u * 1.0 ) / ( 1.0 + 1.0 + 1.0 );
-        let c = ( v * 1.0 + w * 2.0 + u * 0.0 ) / ( 1.0 + 0.0 + 2.0 );
-        let r: f64 = a.max(0.0).min(1.0) * 255.0;
-        let g: f64 = b.max(0.0).min(1.0) * 255.0;
-        let b: f64 = c.max(0.0).min(1.0) * 255.0;
-        img::Rgb([r as u8,g as u8,b as u8])
+impl Pattern {
+    pub fn new(lattice: Lattice, scale: f64) -> Self {
+        Self { lattice, scale, phase: 0.0 }
+    }
+}
+
+// Everything `render` needs. The default is the original 1024 x 1024 square
+// render for base 17 and multiplier 5.
+#[derive(Debug, Clone)]
+pub struct Params {
+    pub view: View,
+    pub pattern: Pattern,
+    pub palette: Palette,
+    pub seed: u64,
+    pub threads: usize,
+}
+
+impl Default for Params {
+    fn default() -> Self {
+        Self {
+            view: View::new(1024, 1024),
+            pattern: Pattern::new(Lattice::Square, 17.0 / 5.0),
+            palette: Palette::default(),
+            seed: 0,
+            threads: thread::available_parallelism().map_or(1, |n| n.get()),
+        }
+    }
+}
+
+impl Params {
+    // The tiling `pattern.lattice` names, with Voronoi cells placed by `seed`.
+    pub fn tiling(&self) -> Box<dyn Tiling> {
+        match self.pattern.lattice {
+            Lattice::Voronoi => Box::new(Voronoi { seed: self.seed }),
+            lattice => Box::new(lattice),
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), MosaicError> {
+        let invalid = |msg: String| Err(MosaicError::InvalidParams(msg));
+        let view = &self.view;
+        if view.width == 0 || view.height == 0 {
+            return invalid(format!("canvas must not be empty, got {}x{}", view.width, view.height));
+        }
+        if !(view.zoom.is_finite() && view.zoom > 0.0) {
+            return invalid(format!("zoom must be positive, got {}", view.zoom));
+        }
+        if !(view.center.0.is_finite() && view.center.1.is_finite()) {
+            return invalid(format!("center must be finite, got {:?}", view.center));
+        }
+        if view.samples == 0 {
+            return invalid("samples must be at least 1".into());
+        }
+        if !(self.pattern.scale.is_finite() && self.pattern.scale != 0.0) {
+            return invalid(format!("pattern scale must be finite and non-zero, got {}", self.pattern.scale));
+        }
+        if self.threads == 0 {
+            return invalid("threads must be at least 1".into());
+        }
+        self.palette.check()
+    }
+}
+
+// Rows per tile. Each tile draws its jitter from its own ChaCha stream, so the
+// image only depends on the seed, never on how tiles land on threads.
+const TILE_ROWS: u32 = 32;
+
+// Renders the 8-bit RGB image described by `params`.
+pub fn render(params: &Params) -> Result<RgbImage, MosaicError> {
+    let kernels = params.palette.kernels(&params.pattern);
+    render_with(params, &*params.tiling(), &kernels)
+}
+
+// Like `render`, but snaps to any tiling and mixes arbitrary kernels instead
+// of the palette's own. The palette needs one weight per kernel in every channel.
+pub fn render_with(params: &Params, lattice: &dyn Tiling, kernels: &[Box<dyn Kernel>]) -> Result<RgbImage, MosaicError> {
+    let data = render_buffer(params, lattice, kernels, 3, |color, _, px: &mut [u8]| {
+        for (p, c) in px.iter_mut().zip(color) {
+            *p = (c * 255.0) as u8;
+        }
+    })?;
+    Ok(ImageBuffer::from_raw(params.view.width, params.view.height, data).expect("buffer matches the view"))
+}
+
+// 16 bits per channel, for compositing without banding.
+pub fn render_rgb16(params: &Params) -> Result<ImageBuffer<Rgb<u16>, Vec<u16>>, MosaicError> {
+    let kernels = params.palette.kernels(&params.pattern);
+    let data = render_buffer(params, &*params.tiling(), &kernels, 3, |color, _, px: &mut [u16]| {
+        for (p, c) in px.iter_mut().zip(color) {
+            *p = (c * 65535.0) as u16;
+        }
+    })?;
+    Ok(ImageBuffer::from_raw(params.view.width, params.view.height, data).expect("buffer matches the view"))
+}
+
+// Colour as 32-bit floats in 0..1, for TIFF/EXR output.
+pub fn render_rgb32f(params: &Params) -> Result<Rgb32FImage, MosaicError> {
+    let kernels = params.palette.kernels(&params.pattern);
+    let data = render_buffer(params, &*params.tiling(), &kernels, 3, |color, _, px: &mut [f32]| {
+        for (p, c) in px.iter_mut().zip(color) {
+            *p = *c as f32;
+        }
+    })?;
+    Ok(ImageBuffer::from_raw(params.view.width, params.view.height, data).expect("buffer matches the view"))
+}
+
+// The filtered kernel values before colour mapping, one channel per palette
+// harmonic, in row-major `height x width x channels` order.
+#[derive(Debug, Clone)]
+pub struct Field {
+    pub width: u32,
+    pub height: u32,
+    pub channels: usize,
+    pub data: Vec<f32>,
+}
+
+impl Field {
+    // Writes the field as a NumPy `.npy` (format 1.0, little-endian f32) array.
+    pub fn write_npy<W: Write>(&self, mut out: W) -> io::Result<()> {
+        let mut header = format!(
+            "{{'descr': '<f4', 'fortran_order': False, 'shape': ({}, {}, {}), }}",
+            self.height, self.width, self.channels
+        );
+        // magic (6) + version (2) + header length (2) + header, padded to 64 bytes
+        let unpadded = 10 + header.len() + 1;
+        header.push_str(&" ".repeat((64 - unpadded % 64) % 64));
+        header.push('\n');
+        out.write_all(b"\x93NUMPY\x01\x00")?;
+        out.write_all(&(header.len() as u16).to_le_bytes())?;
+        out.write_all(header.as_bytes())?;
+        for v in &self.data {
+            out.write_all(&v.to_le_bytes())?;
+        }
+        out.flush()
+    }
+}
+
+pub fn render_field(params: &Params) -> Result<Field, MosaicError> {
+    let kernels = params.palette.kernels(&params.pattern);
+    let channels = kernels.len();
+    let data = render_buffer(params, &*params.tiling(), &kernels, channels, |_, field, px: &mut [f32]| {
+        for (p, v) in px.iter_mut().zip(field) {
+            *p = *v as f32;
+        }
+    })?;
+    Ok(Field { width: params.view.width, height: params.view.height, channels, data })
+}
+
+// File formats `save` can write, picked from the path.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    // `.png` and anything else the image crate knows, 8 bits per channel.
+    Rgb8,
+    // `.16.png`
+    Png16,
+    // `.tif` / `.tiff`, 32-bit float RGB
+    TiffF32,
+    // `.exr`, 32-bit float RGB
+    Exr,
+    // `.npy`, the raw field before colour mapping
+    Npy,
+}
+
+impl OutputFormat {
+    pub fn from_path(path: &Path) -> Self {
+        let ext = path.extension().and_then(|e| e.to_str()).unwrap_or("").to_ascii_lowercase();
+        let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or("");
+        match ext.as_str() {
+            "png" if stem.ends_with(".16") => OutputFormat::Png16,
+            "tif" | "tiff" => OutputFormat::TiffF32,
+            "exr" => OutputFormat::Exr,
+            "npy" => OutputFormat::Npy,
+            _ => OutputFormat::Rgb8,
+        }
+    }
+}
+
+// Renders and writes `path` in the format its extension asks for.
+pub fn save(params: &Params, path: &Path) -> Result<(), MosaicError> {
+    match OutputFormat::from_path(path) {
+        OutputFormat::Rgb8 => render(params)?.save(path)?,
+        OutputFormat::Png16 => render_rgb16(params)?.save_with_format(path, ImageFormat::Png)?,
+        OutputFormat::Exr => render_rgb32f(params)?.save(path)?,
+        OutputFormat::TiffF32 => {
+            let img = render_rgb32f(params)?;
+            let mut encoder = TiffEncoder::new(BufWriter::new(fs::File::create(path)?))?;
+            encoder.write_image::<colortype::RGB32Float>(img.width(), img.height(), img.as_raw())?;
+        }
+        OutputFormat::Npy => render_field(params)?.write_npy(BufWriter::new(fs::File::create(path)?))?,
+    }
+    Ok(())
+}
+
+// Renders the view by splitting the canvas into row tiles spread over
+// `threads` workers. `emit` turns each pixel's filtered colour and field into
+// `channels` samples, written straight into the output buffer, so 16k posters
+// only ever hold the final buffer.
+fn render_buffer<T, E>(params: &Params, lattice: &dyn Tiling, kernels: &[Box<dyn Kernel>], channels: usize, emit: E) -> Result<Vec<T>, MosaicError>
+where
+    T: Copy + Default + Send,
+    E: Fn(&[f64; 3], &[f64], &mut [T]) + Sync,
+{
+    params.validate()?;
+    let weights = params.palette.weights.red.len();
+    if weights != kernels.len() {
+        return Err(MosaicError::InvalidParams(format!("palette has {weights} weights per channel but {} kernels were given", kernels.len())));
+    }
+    let view = &params.view;
+    let mut data = vec![T::default(); view.width as usize * view.height as usize * channels];
+    let tile_len = TILE_ROWS as usize * view.width as usize * channels;
+
+    let mut queues: Vec<Vec<(u64, &mut [T])>> = (0..params.threads).map(|_| vec![]).collect();
+    for (i, tile) in data.chunks_mut(tile_len).enumerate() {
+        queues[i % params.threads].push((i as u64, tile));
+    }
+
+    let emit = &emit;
+    thread::scope(|scope| {
+        for queue in queues {
+            scope.spawn(move || {
+                for (index, tile) in queue {
+                    render_tile(params, lattice, kernels, index, tile, channels, emit);
+                }
+            });
+        }
     });
-    img.save(format!("./tmp/img_sq_{base}_{multiplier}.png")).unwrap();
+    Ok(data)
+}
+
+fn render_tile<T, E>(params: &Params, lattice: &dyn Tiling, kernels: &[Box<dyn Kernel>], index: u64, tile: &mut [T], channels: usize, emit: &E)
+where
+    E: Fn(&[f64; 3], &[f64], &mut [T]),
+{
+    let view = &params.view;
+    let palette = &params.palette;
+    let mut rng = ChaCha8Rng::seed_from_u64(params.seed);
+    rng.set_stream(index);
+    let y0 = index as u32 * TILE_ROWS;
+    let mut values = vec![0.0; kernels.len()];
+    let mut field = vec![0.0; kernels.len()];
+    let mut flat_field = vec![0.0; kernels.len()];
+    let n = view.samples.max(1);
+    let step = 1.0 / n as f64;
+    for (i, px) in tile.chunks_exact_mut(channels).enumerate() {
+        let x = i as u32 % view.width;
+        let y = y0 + i as u32 / view.width;
+        let mut color = [0.0; 3];
+        let mut total = 0.0;
+        // the plain average, in case the filter weights cancel out
+        let mut flat = [0.0; 3];
+        field.fill(0.0);
+        flat_field.fill(0.0);
+        for j in 0..n {
+            for i in 0..n {
+                let r1: f64 = rng.gen();
+                let r2: f64 = rng.gen();
+                let sx = x as f64 + (i as f64 + r1) * step;
+                let sy = y as f64 + (j as f64 + r2) * step;
+                let w = view.filter.weight(sx - x as f64 - 0.5, sy - y as f64 - 0.5);
+                let (x1, y1) = view.to_plane(sx, sy);
+                let (x2, y2) = lattice.centers(x1, y1, 3.0);
+                for (value, kernel) in values.iter_mut().zip(kernels) {
+                    *value = kernel.eval(x2, y2);
+                }
+                let shaded = palette.shade(&values);
+                for c in 0..3 {
+                    color[c] += shaded[c] * w;
+                    flat[c] += shaded[c];
+                }
+                for ((f, g), v) in field.iter_mut().zip(flat_field.iter_mut()).zip(&values) {
+                    *f += v * w;
+                    *g += v;
+                }
+                total += w;
+            }
+        }
+        // signed Lanczos lobes can sum to almost nothing; use a box filter then
+        if total.abs() < MIN_FILTER_TOTAL {
+            color = flat;
+            field.copy_from_slice(&flat_field);
+            total = (n * n) as f64;
+        }
+        let color = color.map(|c| (c / total).clamp(0.0, 1.0));
+        for f in field.iter_mut() {
+            *f /= total;
+        }
+        emit(&color, &field, px);
+    }
+}
+
+// Smallest total filter weight a pixel is normalised by.
+const MIN_FILTER_TOTAL: f64 = 1e-3;
+
+// Largest phase change between frames of a phase loop. Bigger steps jump
+// between unrelated ring positions and the loop strobes instead of moving.
+const MAX_PHASE_STEP: f64 = PI / 4.0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Sweep {
+    // Loops the phase offset once around `Palette::loop_period`.
+    Phase,
+    // Moves pattern_scale linearly to the given value.
+    Scale(f64),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Animation {
+    pub frames: usize,
+    pub sweep: Sweep,
+    pub fps: u32,
+}
+
+// Renders `frames` frames of `sweep` into `dir` as `frame_0000.png`, ... and
+// assembles them into `dir.gif`. Every frame reuses the same seed so only the
+// field moves, not the jitter.
+pub fn animate(params: &Params, animation: Animation, dir: &Path) -> Result<(), MosaicError> {
+    let Animation { frames, sweep, fps } = animation;
+    if frames == 0 {
+        return Err(MosaicError::InvalidParams("an animation needs at least one frame".into()));
+    }
+    // every frame of a phase loop moves by the same step, at most MAX_PHASE_STEP
+    let phase_step = match sweep {
+        Sweep::Phase => {
+            let period = params.palette.loop_period()
+                .ok_or_else(|| MosaicError::InvalidParams("a phase loop needs the osc2 kernel, the others never repeat".into()))?;
+            let needed = (period / MAX_PHASE_STEP).ceil() as usize;
+            if frames < needed {
+                return Err(MosaicError::InvalidParams(format!(
+                    "a phase loop over harmonics {:?} needs at least {needed} frames, got {frames}",
+                    params.palette.harmonics
+                )));
+            }
+            period / frames as f64
+        }
+        Sweep::Scale(_) => 0.0,
+    };
+    fs::create_dir_all(dir)?;
+    let mut frame_params = params.clone();
+    let mut gif_frames = Vec::with_capacity(frames);
+    for k in 0..frames {
+        let pattern = &mut frame_params.pattern;
+        match sweep {
+            Sweep::Phase => {
+                pattern.phase = params.pattern.phase + phase_step * k as f64;
+            }
+            Sweep::Scale(to) => {
+                let t = if frames > 1 { k as f64 / (frames - 1) as f64 } else { 0.0 };
+                pattern.scale = params.pattern.scale + (to - params.pattern.scale) * t;
+            }
+        }
+        let img = render(&frame_params)?;
+        img.save(dir.join(format!("frame_{k:04}.png")))?;
+        let delay = Delay::from_numer_denom_ms(1000, fps.max(1));
+        gif_frames.push(Frame::from_parts(DynamicImage::ImageRgb8(img).into_rgba8(), 0, 0, delay));
+    }
+    let gif = fs::File::create(dir.with_extension("gif"))?;
+    let mut encoder = GifEncoder::new(gif);
+    encoder.set_repeat(Repeat::Infinite)?;
+    encoder.encode_frames(gif_frames)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // FNV-1a over the raw pixel bytes; enough to notice any change in output.
+    fn checksum(img: &RgbImage) -> u64 {
+        img.as_raw().iter().fold(0xcbf29ce484222325, |h, &b| (h ^ b as u64).wrapping_mul(0x100000001b3))
+    }
+
+    fn params(width: u32, height: u32, lattice: Lattice, scale: f64, seed: u64) -> Params {
+        Params {
+            view: View::new(width, height),
+            pattern: Pattern::new(lattice, scale),
+            seed,
+            threads: 1,
+            ..Params::default()
+        }
+    }
+
+    #[test]
+    fn test_same_seed_same_image() {
+        let a = render(&params(32, 32, Lattice::Hex, 17.0 / 5.0, 42)).unwrap();
+        let b = render(&params(32, 32, Lattice::Hex, 17.0 / 5.0, 42)).unwrap();
+        let c = render(&params(32, 32, Lattice::Hex, 17.0 / 5.0, 43)).unwrap();
+        assert_eq!(a, b);
+        assert_ne!(a, c);
+    }
+
+    #[test]
+    fn test_golden_tile() {
+        let img = render(&params(64, 64, Lattice::Square, 20.0 / 11.0, 7)).unwrap();
+        assert_eq!(checksum(&img), GOLDEN_SQ_20_11_SEED_7);
+    }
+
+    #[test]
+    fn test_thread_count_does_not_change_output() {
+        let mut p = params(100, 70, Lattice::Tri, 20.0 / 11.0, 9);
+        p.view.center = (40.0, -25.0);
+        p.view.zoom = 2.5;
+        let single = render(&p).unwrap();
+        p.threads = 5;
+        let many = render(&p).unwrap();
+        assert_eq!(single, many);
+    }
+
+    #[test]
+    fn test_deep_outputs_match_rgb8() {
+        let p = params(24, 16, Lattice::Hex, 20.0 / 11.0, 8);
+        let rgb8 = render(&p).unwrap();
+        let rgb16 = render_rgb16(&p).unwrap();
+        let rgb32 = render_rgb32f(&p).unwrap();
+        for ((a, b), c) in rgb8.as_raw().iter().zip(rgb16.as_raw()).zip(rgb32.as_raw()) {
+            assert_eq!(*a, (*b / 257) as u8);
+            assert_eq!(*a, (*c as f64 * 255.0) as u8);
+        }
+        let field = render_field(&p).unwrap();
+        assert_eq!(field.data.len(), 24 * 16 * 3);
+        assert!(field.data.iter().all(|v| (-1.0..=1.0).contains(v)));
+    }
+
+    #[test]
+    fn test_npy_header() {
+        let field = Field { width: 3, height: 2, channels: 1, data: vec![0.5; 6] };
+        let mut out = vec![];
+        field.write_npy(&mut out).unwrap();
+        let header_len = u16::from_le_bytes([out[8], out[9]]) as usize;
+        assert_eq!(&out[..6], b"\x93NUMPY");
+        assert_eq!((10 + header_len) % 64, 0);
+        assert!(std::str::from_utf8(&out[10..10 + header_len]).unwrap().contains("'shape': (2, 3, 1)"));
+        assert_eq!(out.len(), 10 + header_len + 6 * 4);
+        assert_eq!(OutputFormat::from_path(Path::new("a/b.16.png")), OutputFormat::Png16);
+        assert_eq!(OutputFormat::from_path(Path::new("b.png")), OutputFormat::Rgb8);
+    }
+
+    #[test]
+    fn test_invalid_params_are_reported() {
+        let mut p = params(0, 16, Lattice::Square, 1.0, 0);
+        assert!(matches!(render(&p), Err(MosaicError::InvalidParams(_))));
+        p.view.width = 16;
+        p.palette.weights.green.pop();
+        assert!(matches!(render(&p), Err(MosaicError::Palette(_))));
+    }
+
+    #[test]
+    fn test_filters_keep_flat_fields_flat() {
+        let mut p = params(16, 16, Lattice::Hex, 1.0, 5);
+        p.palette = Palette { harmonics: vec![1], weights: ChannelWeights { red: vec![1.0], green: vec![1.0], blue: vec![1.0] }, ..Palette::default() };
+        p.view.samples = 4;
+        p.threads = 2;
+        let flat: Vec<Box<dyn Kernel>> = vec![Box::new(|_x: f64, _y: f64| 0.2)];
+        for filter in [Filter::Box, Filter::Gaussian, Filter::Lanczos] {
+            p.view.filter = filter;
+            let img = render_with(&p, &Lattice::Hex, &flat).unwrap();
+            // (0.2 + 1) / 2 * 255 = 153, give or take the u8 truncation
+            assert!(img.as_raw().iter().all(|c| c.abs_diff(153) <= 1), "{filter:?}");
+        }
+    }
+
+    #[test]
+    fn test_filters_stay_in_range_on_hard_edges() {
+        let mut p = params(24, 24, Lattice::Square, 1.0, 9);
+        p.palette = Palette { harmonics: vec![1], weights: ChannelWeights { red: vec![1.0], green: vec![1.0], blue: vec![1.0] }, ..Palette::default() };
+        p.view.zoom = 0.37;
+        let edge: Vec<Box<dyn Kernel>> = vec![Box::new(|x: f64, y: f64| if (x + 0.3 * y).sin() < 0.0 { -1.0 } else { 1.0 })];
+        for filter in [Filter::Box, Filter::Gaussian, Filter::Lanczos] {
+            for samples in 1..=4 {
+                p.view.filter = filter;
+                p.view.samples = samples;
+                let data = render_buffer(&p, &Lattice::Square, &edge, 4, |color, field, px: &mut [f64]| {
+                    px[..3].copy_from_slice(color);
+                    px[3] = field[0];
+                })
+                .unwrap();
+                for px in data.chunks_exact(4) {
+                    assert!(px[..3].iter().all(|c| c.is_finite() && (0.0..=1.0).contains(c)), "{filter:?} x{samples}: {px:?}");
+                    assert!(px[3].is_finite() && px[3].abs() <= 1.0 + 1e-9, "{filter:?} x{samples}: {px:?}");
+                }
+                // both sides of the edge show up
+                assert!(data.chunks_exact(4).any(|px| px[0] < 0.01) && data.chunks_exact(4).any(|px| px[0] > 0.99));
+            }
+        }
+    }
+
+    #[test]
+    fn test_penrose_rhombi_cover_the_plane() {
+        let penrose = Penrose::default();
+        let e = Penrose::normals();
+        let mut rng = ChaCha8Rng::seed_from_u64(11);
+        for _ in 0..2000 {
+            let (x, y) = (rng.gen_range(-300.0..300.0), rng.gen_range(-300.0..300.0));
+            // the search never runs out of candidates
+            assert!(penrose.tile(x / 3.0, y / 3.0).is_some(), "({x}, {y})");
+            let (cx, cy) = penrose.centers(x, y, 3.0);
+            // the center of a unit rhombus is at most cos(18°) edge lengths away
+            let d = ((cx - x).powi(2) + (cy - y).powi(2)).sqrt() / 3.0;
+            assert!(d <= (PI / 10.0).cos() + 1e-9, "({x}, {y}) -> ({cx}, {cy})");
+        }
+        assert!(e.iter().map(|n| n.0).sum::<f64>().abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_penrose_centers_never_panic() {
+        let penrose = Penrose::default();
+        // far out, where the rhombus test loses precision, and off the map entirely
+        for (x, y) in [(1e9, -3e9), (1e15, 1e15), (-1e18, 4.5e17), (f64::MAX, 0.0), (f64::NAN, 1.0), (f64::INFINITY, 0.0)] {
+            let (cx, cy) = penrose.centers(x, y, 3.0);
+            if x.is_finite() && x.abs() < 1e16 {
+                assert!((cx - x).abs() < 1e-3 * x.abs() && (cy - y).abs() < 1e-3 * y.abs().max(1.0), "({x}, {y}) -> ({cx}, {cy})");
+            }
+        }
+    }
+
+    #[test]
+    fn test_penrose_has_two_rhombi_in_golden_ratio() {
+        let penrose = Penrose::default();
+        let e = Penrose::normals();
+        let (thick, thin) = ((2.0 * PI / 5.0).sin(), (PI / 5.0).sin());
+        // every tile in a 60 x 60 patch, keyed by its vertex and edge families
+        let mut tiles = std::collections::HashMap::new();
+        for j in 0..240 {
+            for i in 0..240 {
+                let (x, y) = (i as f64 / 4.0 - 30.0, j as f64 / 4.0 - 30.0);
+                let (v, r, s) = penrose.tile(x, y).unwrap();
+                let area = (e[r].0 * e[s].1 - e[r].1 * e[s].0).abs();
+                tiles.insert(((v.0 * 1e6).round() as i64, (v.1 * 1e6).round() as i64, r, s), area);
+            }
+        }
+        let count = |a: f64| tiles.values().filter(|&&area| (area - a).abs() < 1e-9).count();
+        let (n_thick, n_thin) = (count(thick), count(thin));
+        // only the two Penrose rhombi, 72° and 36°
+        assert_eq!(n_thick + n_thin, tiles.len());
+        let golden = (1.0 + 5f64.sqrt()) / 2.0;
+        let ratio = n_thick as f64 / n_thin as f64;
+        assert!((ratio - golden).abs() < 0.05, "{n_thick} thick, {n_thin} thin, ratio {ratio}");
+    }
+
+    #[test]
+    fn test_voronoi_follows_the_seed() {
+        let mut p = params(24, 24, Lattice::Voronoi, 2.0, 1);
+        let a = render(&p).unwrap();
+        p.seed = 2;
+        let b = render(&p).unwrap();
+        assert_ne!(checksum(&a), checksum(&b));
+        assert_eq!(p.tiling().centers(5.5, -3.25, 3.0), Voronoi { seed: 2 }.centers(5.5, -3.25, 3.0));
+    }
+
+    #[test]
+    fn test_voronoi_snaps_to_nearest_feature() {
+        let voronoi = Voronoi { seed: 4 };
+        let mut rng = ChaCha8Rng::seed_from_u64(12);
+        for _ in 0..500 {
+            let (x, y) = (rng.gen_range(-50.0..50.0), rng.gen_range(-50.0..50.0));
+            let (cx, cy) = voronoi.centers(x, y, 2.0);
+            let d = (cx - x).powi(2) + (cy - y).powi(2);
+            let (i0, j0) = ((x / 2.0).floor() as i64, (y / 2.0).floor() as i64);
+            for j in j0 - 3..=j0 + 3 {
+                for i in i0 - 3..=i0 + 3 {
+                    let (fx, fy) = voronoi.feature(i, j);
+                    assert!(d <= (fx * 2.0 - x).powi(2) + (fy * 2.0 - y).powi(2) + 1e-9);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_bessel_asymptotic_range() {
+        // the |x| >= 8 branch, against reference values; sqrt(2/pi) used to be
+        // truncated to 0.636619772 there
+        for (x, j0, j1) in [
+            (8.5, 0.0419392518429345, 0.273121963674054),
+            (10.0, -0.245935764451348, 0.0434727461688614),
+            (20.0, 0.167024664340583, 0.06683312417585),
+            (-50.0, 0.0558123276692518, 0.0975118281251751),
+        ] {
+            assert!((bessel_j0(x) - j0).abs() < 1e-8, "J0({x}) = {}", bessel_j0(x));
+            assert!((bessel_j1(x) - j1).abs() < 1e-8, "J1({x}) = {}", bessel_j1(x));
+        }
+        // pinned tighter than the approximation error, so the truncated
+        // constant (about 7e-11 off here) or any other change shows up
+        let pinned = [-0.24593576445544701, 0.04347274635189323, 0.16702466439805583, 0.06683312403509953];
+        let got = [bessel_j0(10.0), bessel_j1(10.0), bessel_j0(20.0), bessel_j1(20.0)];
+        for (g, p) in got.iter().zip(pinned) {
+            assert!((g - p).abs() < 1e-15, "{got:?}");
+        }
+    }
+
+    #[test]
+    fn test_kernel_combinators() {
+        let ring = Radial { profile: Profile::Osc2, harmonic: 2, scale: 3.0, phase: 0.0 };
+        let half = |_x: f64, _y: f64| 0.5;
+        let (x, y) = (1.5, -2.0);
+        assert_eq!(ring.add(half).eval(x, y), ring.eval(x, y) + 0.5);
+        assert_eq!(ring.mul(half).eval(x, y), ring.eval(x, y) * 0.5);
+        assert_eq!(ring.warp(|x, y| (y, x)).eval(x, y), ring.eval(y, x));
+        assert!((bessel_j0(2.404825557695773)).abs() < 1e-7);
+        assert!((bessel_j1(1.0) - 0.4400505857449335).abs() < 1e-7);
+    }
+
+    #[test]
+    fn test_phase_sweep_loops() {
+        let mut p = params(48, 48, Lattice::Square, 20.0 / 11.0, 3);
+        let start = render(&p).unwrap();
+        p.pattern.phase = p.palette.loop_period().unwrap();
+        let end = render(&p).unwrap();
+        let diff = start.as_raw().iter().zip(end.as_raw()).filter(|(a, b)| a.abs_diff(**b) > 1).count();
+        assert_eq!(diff, 0);
+    }
+
+    #[test]
+    fn test_phase_loop_needs_osc2_and_enough_frames() {
+        let mut p = params(8, 8, Lattice::Square, 2.0, 3);
+        let dir = std::env::temp_dir().join(format!("mosaic_loop_{}", std::process::id()));
+        let phase = |frames| Animation { frames, sweep: Sweep::Phase, fps: 20 };
+        // harmonics 1, 2, 3 repeat every 12π, 48 steps of π/4
+        assert_eq!(p.palette.loop_period(), Some(12.0 * PI));
+        assert!(matches!(animate(&p, phase(47), &dir), Err(MosaicError::InvalidParams(_))));
+        for kernel in [Profile::Osc, Profile::Bessel, Profile::Airy] {
+            p.palette.kernel = kernel;
+            assert_eq!(p.palette.loop_period(), None);
+            assert!(matches!(animate(&p, phase(48), &dir), Err(MosaicError::InvalidParams(_))));
+        }
+        assert!(!dir.exists());
+    }
+
+    // Regenerate with `cargo test test_golden_tile` after an intentional change
+    // to the renderer and copy the value from the failure message.
+    const GOLDEN_SQ_20_11_SEED_7: u64 = 500507242272455013;
 }
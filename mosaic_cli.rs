@@ -0,0 +1,128 @@
+use mosaic::{animate, save, Animation, Filter, Lattice, Palette, Params, Pattern, Sweep};
+use rand::Rng;
+use std::collections::HashMap;
+use std::env;
+use std::fmt::Display;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::str::FromStr;
+
+const USAGE: &str = "usage: mosaic [base] [multiplier] [square|hex|tri|voronoi|penrose] [--palette file] [--seed n] \
+[--width px] [--height px] [--center x,y] [--zoom z] [--samples n] [--filter box|gaussian|lanczos] [--threads n] \
+[--frames n] [--sweep phase|scale] [--to scale] [--fps n] [--output file.png|.16.png|.tif|.exr|.npy]";
+
+const OPTIONS: &[&str] = &[
+    "palette", "seed", "width", "height", "center", "zoom", "samples", "filter", "threads", "frames", "sweep", "to", "fps", "output",
+];
+
+// Splits `--name value` options from positional arguments.
+fn split_args(args: &[String]) -> Result<(Vec<String>, HashMap<String, String>), String> {
+    let mut positional = vec![];
+    let mut options = HashMap::new();
+    let mut it = args.iter();
+    while let Some(arg) = it.next() {
+        match arg.strip_prefix("--") {
+            Some(name) => {
+                if !OPTIONS.contains(&name) {
+                    return Err(format!("unknown option --{name}"));
+                }
+                let value = it.next().ok_or_else(|| format!("--{name} needs a value"))?;
+                options.insert(name.to_string(), value.clone());
+            }
+            None => positional.push(arg.clone()),
+        }
+    }
+    Ok((positional, options))
+}
+
+fn parse<T: FromStr>(name: &str, value: &str) -> Result<T, String>
+where
+    T::Err: Display,
+{
+    value.parse::<T>().map_err(|e| format!("invalid {name} '{value}': {e}"))
+}
+
+// Parses `--name value`, falling back to `default` when the option is absent.
+fn option<T: FromStr>(options: &HashMap<String, String>, name: &str, default: T) -> Result<T, String>
+where
+    T::Err: Display,
+{
+    match options.get(name) {
+        Some(value) => parse(name, value),
+        None => Ok(default),
+    }
+}
+
+fn run() -> Result<(), String> {
+    let (args, options) = split_args(&env::args().skip(1).collect::<Vec<_>>())?;
+    if args.len() > 3 {
+        return Err(format!("unexpected argument '{}'", args[3]));
+    }
+
+    let base = args.first().map_or(Ok(17.0), |v| parse::<f64>("base", v))?;
+    let multiplier = args.get(1).map_or(Ok(5.0), |v| parse::<f64>("multiplier", v))?;
+    let lattice = args.get(2).map_or(Ok(Lattice::Square), |v| parse::<Lattice>("lattice", v))?;
+
+    let mut params = Params {
+        pattern: Pattern::new(lattice, base / multiplier),
+        ..Params::default()
+    };
+    println!("Using pattern scale: {}", params.pattern.scale);
+
+    if let Some(path) = options.get("palette") {
+        params.palette = Palette::load(Path::new(path)).map_err(|e| format!("{path}: {e}"))?;
+    }
+
+    params.seed = option(&options, "seed", rand::thread_rng().gen())?;
+    println!("Using seed: {}", params.seed);
+
+    params.view.width = option(&options, "width", params.view.width)?;
+    params.view.height = option(&options, "height", params.view.height)?;
+    params.view.zoom = option(&options, "zoom", params.view.zoom)?;
+    if let Some(center) = options.get("center") {
+        let (x, y) = center.split_once(',').ok_or_else(|| format!("invalid center '{center}': expected x,y"))?;
+        params.view.center = (parse("center x", x)?, parse("center y", y)?);
+    }
+    params.view.samples = option(&options, "samples", params.view.samples)?;
+    params.view.filter = option(&options, "filter", Filter::Box)?;
+    params.threads = option(&options, "threads", params.threads)?;
+    params.validate().map_err(|e| e.to_string())?;
+
+    let out = Path::new("./tmp");
+    fs::create_dir_all(out).map_err(|e| format!("cannot create {}: {e}", out.display()))?;
+
+    if let Some(frames) = options.get("frames") {
+        let sweep = match options.get("sweep").map(String::as_str) {
+            None | Some("phase") => Sweep::Phase,
+            Some("scale") => Sweep::Scale(option(&options, "to", params.pattern.scale * 2.0)?),
+            Some(other) => return Err(format!("unknown sweep '{other}', expected phase or scale")),
+        };
+        let animation = Animation { frames: parse("frames", frames)?, sweep, fps: option(&options, "fps", 20)? };
+        let dir = out.join(format!("anim_{}_{base}_{multiplier}", lattice.tag()));
+        animate(&params, animation, &dir).map_err(|e| e.to_string())?;
+        println!("Wrote {} frames to {}", animation.frames, dir.display());
+        return Ok(());
+    }
+
+    let path = match options.get("output") {
+        Some(path) => PathBuf::from(path),
+        None => out.join(format!("img_{}_{base}_{multiplier}.png", lattice.tag())),
+    };
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(|e| format!("cannot create {}: {e}", dir.display()))?;
+    }
+    save(&params, &path).map_err(|e| format!("cannot write {}: {e}", path.display()))?;
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    match run() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("mosaic: {e}");
+            eprintln!("{USAGE}");
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,214 @@
+use crate::SAMPLE_RATE;
+use rand::prelude::*;
+use rand_chacha::ChaCha8Rng;
+use rustfft::num_complex::Complex;
+use std::f64::consts::{FRAC_PI_2, PI};
+
+// Plays a stereo sample, such as a `Subtractive` preset, as an instrument.
+// Pitch comes from resampling: a note at `root` Hz plays the sample as it is,
+// an octave up plays it twice as fast. Looped samples have their crossfade
+// baked in, so reading them round and round never clicks. Notes above the
+// root are low-passed as they are read, so what the faster playback would
+// push past Nyquist is removed instead of folding back down.
+pub struct Sampler {
+    left: Vec<f64>,
+    right: Vec<f64>,
+    looping: bool,
+    // frequency at which the sample plays at its own speed
+    pub root: f64,
+    // each note starts at a random point in the first `jitter` of the sample
+    // (0 to 1), so repeated notes don't sound identical
+    pub jitter: f64,
+    pub seed: u64,
+}
+
+impl Sampler {
+    // Plays `left` and `right` once. With `crossfade` the sample loops
+    // instead: its last `crossfade` frames are faded into its first, and the
+    // loop is that much shorter.
+    pub fn new(left: &[f64], right: &[f64], crossfade: Option<usize>) -> Self {
+        let len = left.len().min(right.len());
+        let (left, right) = match crossfade {
+            Some(frames) => (looped(&left[..len], frames), looped(&right[..len], frames)),
+            None => (left[..len].to_vec(), right[..len].to_vec()),
+        };
+        Sampler { left, right, looping: crossfade.is_some(), root: 440.0, jitter: 0.0, seed: 0 }
+    }
+
+    // A preset's real parts, each channel centred on zero (raw noise sits on
+    // a DC offset of a half).
+    pub fn from_preset(preset: &(Vec<Complex<f64>>, Vec<Complex<f64>>), crossfade: Option<usize>) -> Self {
+        let centre = |v: &[Complex<f64>]| {
+            let mean = v.iter().map(|c| c.re).sum::<f64>() / v.len().max(1) as f64;
+            v.iter().map(|c| c.re - mean).collect::<Vec<_>>()
+        };
+        Self::new(&centre(&preset.0), &centre(&preset.1), crossfade)
+    }
+
+    // Frames in the sample, or in one pass of the loop.
+    pub fn len(&self) -> usize {
+        self.left.len()
+    }
+
+    // Both channels at fractional frame `pos`, by cubic interpolation. A
+    // looped sample wraps round; otherwise it is silent outside its length.
+    pub fn at(&self, pos: f64) -> (f64, f64) {
+        if !self.playing(pos) {
+            return (0.0, 0.0);
+        }
+        let i = pos.floor() as i64;
+        let f = pos - i as f64;
+        let (a, b, c, d) = (self.frame(i - 1), self.frame(i), self.frame(i + 1), self.frame(i + 2));
+        (cubic(a.0, b.0, c.0, d.0, f), cubic(a.1, b.1, c.1, d.1, f))
+    }
+
+    // `at` for playback moving `step` frames per output frame. Above 1 the
+    // sample is read through a Hann-windowed sinc with its cutoff lowered to
+    // the output's Nyquist, 1 / (2 step) cycles per frame.
+    pub fn at_speed(&self, pos: f64, step: f64) -> (f64, f64) {
+        if step <= 1.0 {
+            return self.at(pos);
+        }
+        if !self.playing(pos) {
+            return (0.0, 0.0);
+        }
+        let half = SINC_ZEROS * step;
+        let (mut left, mut right, mut total) = (0.0, 0.0, 0.0);
+        for k in (pos - half).ceil() as i64..=(pos + half).floor() as i64 {
+            let x = (pos - k as f64) / step;
+            let w = sinc(x) * (0.5 + 0.5 * (PI * x / SINC_ZEROS).cos());
+            let (l, r) = self.frame(k);
+            left += l * w;
+            right += r * w;
+            total += w;
+        }
+        (left / total, right / total)
+    }
+
+    fn playing(&self, pos: f64) -> bool {
+        self.len() > 0 && (self.looping || (0.0..self.len() as f64).contains(&pos))
+    }
+
+    // Frame `k`, wrapped round a loop or silent outside a one-shot.
+    fn frame(&self, k: i64) -> (f64, f64) {
+        let len = self.len() as i64;
+        if self.looping {
+            let k = k.rem_euclid(len) as usize;
+            (self.left[k], self.right[k])
+        } else if (0..len).contains(&k) {
+            (self.left[k as usize], self.right[k as usize])
+        } else {
+            (0.0, 0.0)
+        }
+    }
+
+    // Note `index` at `freq`, as a signal of the time since it started. The
+    // start offset is drawn from `seed` and the index, so a render is the
+    // same every time.
+    pub fn voice(&self, freq: f64, index: usize) -> impl Fn(f64) -> (f64, f64) + '_ {
+        let speed = freq / self.root * SAMPLE_RATE as f64;
+        let mut rng = ChaCha8Rng::seed_from_u64(self.seed.wrapping_add(index as u64));
+        let start = rng.gen::<f64>() * self.jitter.clamp(0.0, 1.0) * self.len() as f64;
+        move |t| self.at_speed(start + t * speed, speed / SAMPLE_RATE as f64)
+    }
+}
+
+// Zero crossings of the resampling sinc on each side of the read position.
+const SINC_ZEROS: f64 = 8.0;
+
+fn sinc(x: f64) -> f64 {
+    if x == 0.0 { 1.0 } else { (PI * x).sin() / (PI * x) }
+}
+
+// Equal-power crossfade of the tail into the head: the noise in the two is
+// unrelated, so powers rather than amplitudes should sum to one.
+fn looped(samples: &[f64], crossfade: usize) -> Vec<f64> {
+    let fade = crossfade.min(samples.len() / 2);
+    let len = samples.len() - fade;
+    let mut out = samples[..len].to_vec();
+    for (i, y) in out.iter_mut().take(fade).enumerate() {
+        let x = (i as f64 + 0.5) / fade as f64 * FRAC_PI_2;
+        *y = *y * x.sin() + samples[len + i] * x.cos();
+    }
+    out
+}
+
+// Catmull-Rom spline through b and c at `f` from 0 to 1.
+fn cubic(a: f64, b: f64, c: f64, d: f64, f: f64) -> f64 {
+    b + 0.5 * f * (c - a + f * (2.0 * a - 5.0 * b + 4.0 * c - d + f * (3.0 * (b - c) + d - a)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::subtractive::{Looping, Recipe, Subtractive};
+
+    fn sine(freq: f64, frames: usize) -> Vec<f64> {
+        (0..frames).map(|i| (2.0 * PI * freq * i as f64 / SAMPLE_RATE as f64).sin()).collect()
+    }
+
+    #[test]
+    fn test_resampling_shifts_pitch() {
+        let tone = sine(441.0, 44_100);
+        let sampler = Sampler::new(&tone, &tone, Some(0));
+        // a fifth up plays 441 Hz as 661.5 Hz
+        let voice = sampler.voice(660.0, 0);
+        let shifted = sine(441.0 * 1.5, 4410);
+        for (i, y) in shifted.iter().enumerate() {
+            assert!((voice(i as f64 / SAMPLE_RATE as f64).0 - y).abs() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn test_shifting_up_does_not_fold_back() {
+        // an octave up, 15 kHz would play at 30 kHz and fold down to 14.1 kHz
+        let power = |voice: &dyn Fn(f64) -> (f64, f64)| {
+            (0..4410).map(|i| voice(i as f64 / SAMPLE_RATE as f64).0.powi(2)).sum::<f64>() / 4410.0
+        };
+        let high = sine(15_000.0, 44_100);
+        let sampler = Sampler::new(&high, &high, Some(0));
+        let naive = power(&|t| sampler.at(t * 2.0 * SAMPLE_RATE as f64));
+        let shifted = power(&sampler.voice(880.0, 0));
+        assert!(naive > 0.1, "{naive}");
+        assert!(shifted < 1e-5, "{shifted}");
+
+        // while 5 kHz goes up to 10 kHz untouched
+        let low = sine(5000.0, 44_100);
+        let sampler = Sampler::new(&low, &low, Some(0));
+        let voice = sampler.voice(880.0, 0);
+        let up = sine(10_000.0, 4410);
+        assert!(up.iter().enumerate().all(|(i, y)| (voice(i as f64 / SAMPLE_RATE as f64).0 - y).abs() < 0.01));
+    }
+
+    #[test]
+    fn test_crossfaded_loop_is_smooth() {
+        // a ramp jumps back down at its end unless the loop is crossfaded
+        let ramp: Vec<f64> = (0..1000).map(|i| i as f64 / 1000.0).collect();
+        let jump = |s: &Sampler| (0..3000).map(|i| (s.at(i as f64 + 1.0).0 - s.at(i as f64).0).abs()).fold(0.0, f64::max);
+        let hard = Sampler::new(&ramp, &ramp, Some(0));
+        let faded = Sampler::new(&ramp, &ramp, Some(200));
+        assert!(jump(&hard) > 0.9);
+        assert!(jump(&faded) < 0.01, "{}", jump(&faded));
+        assert_eq!(faded.len(), 800);
+        // a one-shot stops at its end
+        let once = Sampler::new(&ramp, &ramp, None);
+        assert_eq!(once.at(1000.5), (0.0, 0.0));
+        assert!((once.at(500.25).0 - 0.50025).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_start_jitter_is_seeded() {
+        let mut sub = Subtractive::new();
+        sub.add(Recipe::new(5, 4410, Looping::Seamless));
+        let mut sampler = Sampler::from_preset(&sub.preset[0], Some(441));
+        let first = |s: &Sampler, index| s.voice(440.0, index)(0.0);
+        // without jitter every note starts at the top
+        assert_eq!(first(&sampler, 0), first(&sampler, 1));
+        sampler.jitter = 1.0;
+        assert_ne!(first(&sampler, 0), first(&sampler, 1));
+        assert_eq!(first(&sampler, 2), first(&sampler, 2));
+        // centred
+        let mean = (0..sampler.len()).map(|i| sampler.at(i as f64).0).sum::<f64>() / sampler.len() as f64;
+        assert!(mean.abs() < 0.01, "{mean}");
+    }
+}
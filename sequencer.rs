@@ -0,0 +1,539 @@
+use crate::envelope::Envelope;
+use crate::signal::{self, Signal};
+use crate::tuning::Scale;
+use crate::SAMPLE_RATE;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+
+// Fade applied after a note's end (or when its voice is stolen) so it doesn't click.
+const RELEASE: f64 = 0.02;
+// MIDI key that sounds at the scale's root, A4 = 440 Hz.
+const ROOT_KEY: i32 = 69;
+const ROOT_FREQ: f64 = 440.0;
+
+#[derive(Debug)]
+pub enum SequenceError {
+    Midi(String),
+    Score(String),
+    Io(io::Error),
+}
+
+impl fmt::Display for SequenceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SequenceError::Midi(msg) => write!(f, "midi error: {}", msg),
+            SequenceError::Score(msg) => write!(f, "score error: {}", msg),
+            SequenceError::Io(e) => write!(f, "io error: {}", e),
+        }
+    }
+}
+
+impl Error for SequenceError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            SequenceError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for SequenceError {
+    fn from(e: io::Error) -> Self {
+        SequenceError::Io(e)
+    }
+}
+
+// Times are in seconds, velocity is 0..=1.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Note {
+    pub start: f64,
+    pub duration: f64,
+    pub key: u8,
+    pub velocity: f64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Score {
+    pub notes: Vec<Note>,
+}
+
+impl Score {
+    // `.mid`/`.midi` files are read as standard MIDI files, anything else as a text score.
+    pub fn load(path: &Path) -> Result<Score, SequenceError> {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("mid") | Some("midi") => Score::from_midi(&fs::read(path)?),
+            _ => Score::from_text(&fs::read_to_string(path)?),
+        }
+    }
+
+    pub fn end(&self) -> f64 {
+        self.notes.iter().fold(0.0, |end, n| end.max(n.start + n.duration))
+    }
+
+    // One note per line as `beat length key [velocity]`, where `key` is a MIDI
+    // number or a name like `A4`, `C#3` or `Bb2` and velocity is 1..=127
+    // (default 100). `bpm N` sets the tempo for the lines after it (default
+    // 120) and `#` starts a comment.
+    pub fn from_text(text: &str) -> Result<Score, SequenceError> {
+        let mut bpm = 120.0;
+        let mut notes = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            let bad = |what: &str| SequenceError::Score(format!("line {}: {}", i + 1, what));
+            let fields: Vec<&str> = line.split_whitespace().take_while(|f| !f.starts_with('#')).collect();
+            match fields.as_slice() {
+                [] => {}
+                ["bpm", value] => {
+                    bpm = value.parse::<f64>().ok().filter(|b| *b > 0.0).ok_or_else(|| bad("bad bpm"))?;
+                }
+                [beat, length, key, rest @ ..] if rest.len() <= 1 => {
+                    let beat = beat.parse::<f64>().ok().filter(|b| *b >= 0.0).ok_or_else(|| bad("bad beat"))?;
+                    let length = length.parse::<f64>().ok().filter(|l| *l > 0.0).ok_or_else(|| bad("bad length"))?;
+                    let key = parse_key(key).ok_or_else(|| bad("bad key"))?;
+                    let velocity = match rest.first() {
+                        Some(v) => v.parse::<u8>().ok().filter(|v| (1..=127).contains(v)).ok_or_else(|| bad("bad velocity"))?,
+                        None => 100,
+                    };
+                    let beat_secs = 60.0 / bpm;
+                    notes.push(Note { start: beat * beat_secs, duration: length * beat_secs, key, velocity: velocity as f64 / 127.0 });
+                }
+                _ => return Err(bad("expected `beat length key [velocity]` or `bpm N`")),
+            }
+        }
+        notes.sort_by(|a, b| a.start.total_cmp(&b.start));
+        Ok(Score { notes })
+    }
+
+    // Reads note on/off and tempo events from a format 0 or 1 file; every
+    // channel and track is merged into one score.
+    pub fn from_midi(bytes: &[u8]) -> Result<Score, SequenceError> {
+        let mut reader = Reader { bytes, pos: 0 };
+        if reader.take(4)? != b"MThd" || reader.u32()? != 6 {
+            return Err(SequenceError::Midi("missing MThd header".into()));
+        }
+        let format = reader.u16()?;
+        let tracks = reader.u16()?;
+        let division = reader.u16()?;
+        if format > 1 {
+            return Err(SequenceError::Midi(format!("format {} is not supported", format)));
+        }
+        if division & 0x8000 != 0 || division == 0 {
+            return Err(SequenceError::Midi("SMPTE time division is not supported".into()));
+        }
+
+        // (tick, microseconds per quarter) and (tick, channel, key, velocity, on)
+        let mut tempos: Vec<(u64, u32)> = Vec::new();
+        let mut events: Vec<(u64, u8, u8, u8, bool)> = Vec::new();
+        for _ in 0..tracks {
+            let id = reader.take(4)?;
+            let len = reader.u32()? as usize;
+            let chunk = reader.take(len)?;
+            if id == b"MTrk" {
+                read_track(chunk, &mut tempos, &mut events)?;
+            }
+        }
+
+        tempos.sort_by_key(|t| t.0);
+        // stable, so a note-off and note-on on the same tick keep file order
+        events.sort_by_key(|e| e.0);
+        let seconds = |tick: u64| {
+            let (mut secs, mut last_tick, mut tempo) = (0.0, 0, 500_000);
+            for &(at, next) in tempos.iter().take_while(|t| t.0 < tick) {
+                secs += (at - last_tick) as f64 * tempo as f64 / 1e6 / division as f64;
+                last_tick = at;
+                tempo = next;
+            }
+            secs + (tick - last_tick) as f64 * tempo as f64 / 1e6 / division as f64
+        };
+
+        let mut held: Vec<(u8, u8, u64, u8)> = Vec::new();
+        let mut notes = Vec::new();
+        let mut close = |(_, key, start, velocity): (u8, u8, u64, u8), end: u64| {
+            let start_secs = seconds(start);
+            notes.push(Note { start: start_secs, duration: seconds(end) - start_secs, key, velocity: velocity as f64 / 127.0 });
+        };
+        for &(tick, channel, key, velocity, on) in &events {
+            if on {
+                held.push((channel, key, tick, velocity));
+            } else if let Some(i) = held.iter().position(|h| h.0 == channel && h.1 == key) {
+                close(held.remove(i), tick);
+            }
+        }
+        // notes still held at the end of the file last until the final event
+        let last = events.last().map_or(0, |e| e.0);
+        for h in held {
+            close(h, last);
+        }
+        notes.retain(|n| n.duration > 0.0);
+        notes.sort_by(|a, b| a.start.total_cmp(&b.start));
+        Ok(Score { notes })
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], SequenceError> {
+        let end = self.pos.checked_add(n).filter(|end| *end <= self.bytes.len());
+        let end = end.ok_or_else(|| SequenceError::Midi("unexpected end of file".into()))?;
+        let slice = &self.bytes[self.pos..end];
+        self.pos = end;
+        Ok(slice)
+    }
+
+    fn u8(&mut self) -> Result<u8, SequenceError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, SequenceError> {
+        let b = self.take(2)?;
+        Ok(u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, SequenceError> {
+        let b = self.take(4)?;
+        Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    // variable-length quantity, at most four bytes
+    fn vlq(&mut self) -> Result<u32, SequenceError> {
+        let mut value = 0_u32;
+        for _ in 0..4 {
+            let b = self.u8()?;
+            value = (value << 7) | (b & 0x7f) as u32;
+            if b & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(SequenceError::Midi("variable-length quantity longer than four bytes".into()))
+    }
+
+    fn done(&self) -> bool {
+        self.pos >= self.bytes.len()
+    }
+}
+
+fn read_track(
+    chunk: &[u8],
+    tempos: &mut Vec<(u64, u32)>,
+    events: &mut Vec<(u64, u8, u8, u8, bool)>,
+) -> Result<(), SequenceError> {
+    let mut reader = Reader { bytes: chunk, pos: 0 };
+    let mut tick = 0_u64;
+    let mut running: Option<u8> = None;
+    while !reader.done() {
+        tick += reader.vlq()? as u64;
+        let mut status = reader.u8()?;
+        let mut first = None;
+        if status < 0x80 {
+            // running status: this byte is already the first data byte
+            first = Some(status);
+            status = running.ok_or_else(|| SequenceError::Midi("data byte without a status".into()))?;
+        }
+        match status {
+            0xff => {
+                let kind = reader.u8()?;
+                let len = reader.vlq()? as usize;
+                let data = reader.take(len)?;
+                match kind {
+                    0x51 if len == 3 => tempos.push((tick, u32::from_be_bytes([0, data[0], data[1], data[2]]))),
+                    0x2f => return Ok(()),
+                    _ => {}
+                }
+                running = None;
+            }
+            0xf0 | 0xf7 => {
+                let len = reader.vlq()? as usize;
+                reader.take(len)?;
+                running = None;
+            }
+            0x80..=0xef => {
+                running = Some(status);
+                let a = match first {
+                    Some(a) => a,
+                    None => reader.u8()?,
+                };
+                let kind = status & 0xf0;
+                if kind == 0xc0 || kind == 0xd0 {
+                    continue;
+                }
+                let b = reader.u8()?;
+                let channel = status & 0x0f;
+                match kind {
+                    0x90 if b > 0 => events.push((tick, channel, a, b, true)),
+                    0x80 | 0x90 => events.push((tick, channel, a, 0, false)),
+                    _ => {}
+                }
+            }
+            _ => return Err(SequenceError::Midi(format!("unexpected status byte {:#04x}", status))),
+        }
+    }
+    Ok(())
+}
+
+fn parse_key(s: &str) -> Option<u8> {
+    if let Ok(key) = s.parse::<u8>() {
+        return (key < 128).then_some(key);
+    }
+    let mut chars = s.chars();
+    let class = match chars.next()?.to_ascii_uppercase() {
+        'C' => 0,
+        'D' => 2,
+        'E' => 4,
+        'F' => 5,
+        'G' => 7,
+        'A' => 9,
+        'B' => 11,
+        _ => return None,
+    };
+    let rest = chars.as_str();
+    let (shift, octave) = match rest.as_bytes().first() {
+        Some(b'#') => (1, &rest[1..]),
+        Some(b'b') => (-1, &rest[1..]),
+        _ => (0, rest),
+    };
+    let key = 12 * (octave.parse::<i32>().ok()? + 1) + class + shift;
+    u8::try_from(key).ok().filter(|k| *k < 128)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Voice {
+    Sine,
+    Tri,
+    Sharp,
+}
+
+impl Voice {
+    fn osc(self, freq: f64) -> Box<dyn Signal> {
+        match self {
+            Voice::Sine => signal::sine(freq).boxed(),
+            Voice::Tri => signal::tri(freq).boxed(),
+            Voice::Sharp => signal::sharp(0.95, freq).boxed(),
+        }
+    }
+}
+
+impl FromStr for Voice {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Voice, String> {
+        match s {
+            "sine" => Ok(Voice::Sine),
+            "tri" => Ok(Voice::Tri),
+            "sharp" => Ok(Voice::Sharp),
+            _ => Err(format!("unknown voice '{}', expected sine, tri or sharp", s)),
+        }
+    }
+}
+
+// A note after voice allocation; `end` is earlier than the note's own end if
+// its voice was stolen, and the voice keeps sounding for `tail` after it
+// while the envelope releases (cut short if stolen).
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Slot {
+    note: Note,
+    end: f64,
+    tail: f64,
+}
+
+// Plays a score on up to `polyphony` voices, each an oscillator shaped by
+// `envelope` (or by `env` if there is none) and scaled by velocity. Keys are
+// degrees of `scale` counted from A4 = 440 Hz, so `12-tet` is standard tuning.
+pub struct Sequencer {
+    pub voice: Voice,
+    pub scale: Scale,
+    pub polyphony: usize,
+    pub oversample: usize,
+    // how fast `env` decays; higher is more percussive
+    pub decay: f64,
+    pub envelope: Option<Envelope>,
+}
+
+impl Sequencer {
+    pub fn new(voice: Voice, scale: Scale) -> Sequencer {
+        Sequencer { voice, scale, polyphony: 8, oversample: 1, decay: 4.0, envelope: None }
+    }
+
+    fn tail(&self) -> f64 {
+        match &self.envelope {
+            Some(Envelope { release: Some(release), .. }) => release.iter().map(|s| s.duration).sum(),
+            _ => 0.0,
+        }
+    }
+
+    // Gives each note a voice, stealing the one that started earliest when
+    // all are busy.
+    fn allocate(&self, score: &Score) -> Vec<Slot> {
+        let mut slots: Vec<Slot> = Vec::with_capacity(score.notes.len());
+        let mut voices: Vec<usize> = Vec::with_capacity(self.polyphony);
+        let tail = self.tail();
+        for note in &score.notes {
+            voices.retain(|&i| slots[i].end + slots[i].tail > note.start);
+            if voices.len() >= self.polyphony.max(1) {
+                let oldest = &mut slots[voices.remove(0)];
+                oldest.tail = (note.start - oldest.end).max(0.0);
+                oldest.end = oldest.end.min(note.start);
+            }
+            voices.push(slots.len());
+            slots.push(Slot { note: *note, end: note.start + note.duration, tail });
+        }
+        slots
+    }
+
+    pub fn render(&self, score: &Score) -> Vec<f64> {
+        let [out] = self.play(score, |freq, _| {
+            let osc = match self.oversample {
+                0 | 1 => self.voice.osc(freq),
+                factor => self.voice.osc(freq).band_limited(factor).boxed(),
+            };
+            Box::new(move |t| [osc.at(t)])
+        });
+        out
+    }
+
+    // Like `render`, but each note is played by `voice(freq, index)`, a stereo
+    // signal of the time since the note started, instead of `self.voice`.
+    pub fn render_stereo<'a, V>(&self, score: &Score, voice: V) -> (Vec<f64>, Vec<f64>)
+    where
+        V: Fn(f64, usize) -> Box<dyn Fn(f64) -> (f64, f64) + 'a>,
+    {
+        let [left, right] = self.play(score, |freq, index| {
+            let source = voice(freq, index);
+            Box::new(move |t: f64| {
+                let (l, r) = source(t);
+                [l, r]
+            })
+        });
+        (left, right)
+    }
+
+    fn play<'a, const N: usize, V>(&self, score: &Score, voice: V) -> [Vec<f64>; N]
+    where
+        V: Fn(f64, usize) -> Box<dyn Fn(f64) -> [f64; N] + 'a>,
+    {
+        let rate = SAMPLE_RATE as f64;
+        let length = ((score.end() + self.tail() + RELEASE) * rate).ceil() as usize;
+        let mut out: [Vec<f64>; N] = std::array::from_fn(|_| vec![0.0; length]);
+        for (index, slot) in self.allocate(score).into_iter().enumerate() {
+            let freq = ROOT_FREQ * self.scale.ratio(slot.note.key as i32 - ROOT_KEY);
+            let source = voice(freq, index);
+            let held = slot.end - slot.note.start;
+            let ring = held + slot.tail;
+            // one decay of `env` spans the whole note
+            let decay = signal::env(2.0 * (ring + RELEASE), self.decay);
+            // a voice stolen before its release is faded out instead
+            let gate = if slot.tail > 0.0 { held } else { f64::INFINITY };
+            let level = |t: f64| match &self.envelope {
+                Some(envelope) => envelope.level(envelope.start, t, gate),
+                None => decay.at(t),
+            };
+            let first = (slot.note.start * rate).ceil() as usize;
+            let last = (((slot.end + slot.tail + RELEASE) * rate).ceil() as usize).min(length);
+            for i in first..last {
+                let t = i as f64 / rate - slot.note.start;
+                let fade = ((ring + RELEASE - t) / RELEASE).clamp(0.0, 1.0);
+                let gain = slot.note.velocity * level(t) * fade;
+                for (channel, y) in out.iter_mut().zip(source(t)) {
+                    channel[i] += gain * y;
+                }
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_text_score() {
+        let score = Score::from_text("# two notes\nbpm 60\n0 1 A4\n  1 0.5 C#5 64 # second\n\nbpm 120\n4 1 60 127\n").unwrap();
+        assert_eq!(
+            score.notes,
+            vec![
+                Note { start: 0.0, duration: 1.0, key: 69, velocity: 100.0 / 127.0 },
+                Note { start: 1.0, duration: 0.5, key: 73, velocity: 64.0 / 127.0 },
+                Note { start: 2.0, duration: 0.5, key: 60, velocity: 1.0 },
+            ]
+        );
+        assert_eq!(parse_key("Bb-1"), Some(10));
+        assert_eq!(parse_key("G9"), Some(127));
+        assert_eq!(parse_key("G#9"), None);
+        assert!(matches!(Score::from_text("0 1 H4"), Err(SequenceError::Score(_))));
+        assert!(matches!(Score::from_text("0 0 A4"), Err(SequenceError::Score(_))));
+    }
+
+    #[test]
+    fn test_midi_file() {
+        // format 0, 96 ticks per quarter
+        let mut track = vec![
+            0x00, 0xff, 0x51, 0x03, 0x07, 0xa1, 0x20, // 120 bpm
+            0x00, 0x90, 60, 100, // C4 on
+            0x00, 64, 80, // E4 on, running status
+            0x60, 60, 0, // C4 off as a zero-velocity note on, one beat later
+            0x00, 0xff, 0x51, 0x03, 0x0f, 0x42, 0x40, // 60 bpm
+            0x60, 0x80, 64, 0, // E4 off one (slower) beat later
+            0x00, 0xff, 0x2f, 0x00,
+        ];
+        let mut bytes = b"MThd\x00\x00\x00\x06\x00\x00\x00\x01\x00\x60MTrk".to_vec();
+        bytes.extend_from_slice(&(track.len() as u32).to_be_bytes());
+        bytes.append(&mut track);
+
+        let score = Score::from_midi(&bytes).unwrap();
+        assert_eq!(
+            score.notes,
+            vec![
+                Note { start: 0.0, duration: 0.5, key: 60, velocity: 100.0 / 127.0 },
+                Note { start: 0.0, duration: 1.5, key: 64, velocity: 80.0 / 127.0 },
+            ]
+        );
+        assert!(matches!(Score::from_midi(&bytes[..20]), Err(SequenceError::Midi(_))));
+    }
+
+    #[test]
+    fn test_voices_are_stolen_oldest_first() {
+        let note = |start: f64, key: u8| Note { start, duration: 1.0, key, velocity: 1.0 };
+        let score = Score { notes: vec![note(0.0, 60), note(0.1, 64), note(0.2, 67), note(1.5, 72)] };
+        let mut sequencer = Sequencer::new(Voice::Sine, Scale::equal(12));
+        sequencer.polyphony = 2;
+        let ends: Vec<f64> = sequencer.allocate(&score).iter().map(|s| s.end).collect();
+        assert_eq!(ends, vec![0.2, 1.1, 1.2, 2.5]);
+    }
+
+    #[test]
+    fn test_adsr_voices_release_after_the_note() {
+        let score = Score::from_text("bpm 60\n0 0.5 A4 127\n").unwrap();
+        let mut sequencer = Sequencer::new(Voice::Sine, Scale::equal(12));
+        sequencer.envelope = Some(Envelope::adsr(0.01, 0.1, 0.5, 0.25));
+        let samples = sequencer.render(&score);
+        let rate = SAMPLE_RATE as f64;
+        assert_eq!(samples.len(), ((0.75 + RELEASE) * rate).ceil() as usize);
+        let peak = |from: f64, to: f64| samples[(from * rate) as usize..(to * rate) as usize].iter().fold(0_f64, |m, x| m.max(x.abs()));
+        assert!(peak(0.0, 0.02) > 0.9);
+        assert!((peak(0.4, 0.5) - 0.5).abs() < 0.01);
+        assert!(peak(0.55, 0.6) < 0.5 && peak(0.55, 0.6) > 0.01);
+        assert!(peak(0.74, 0.75) < 0.01);
+    }
+
+    #[test]
+    fn test_render_places_notes() {
+        let score = Score::from_text("bpm 60\n0 0.5 A4\n1 0.5 A5 127\n").unwrap();
+        let samples = Sequencer::new(Voice::Sine, Scale::equal(12)).render(&score);
+        let rate = SAMPLE_RATE as f64;
+        assert_eq!(samples.len(), ((1.5 + RELEASE) * rate).ceil() as usize);
+        let peak = |from: f64, to: f64| samples[(from * rate) as usize..(to * rate) as usize].iter().fold(0_f64, |m, x| m.max(x.abs()));
+        assert!(peak(0.0, 0.1) > 0.5);
+        assert_eq!(peak(0.5 + RELEASE + 0.001, 1.0), 0.0);
+        assert!(peak(1.0, 1.1) > 0.9);
+        // A5 crosses zero twice as often as A4
+        let crossings = |from: f64| samples[(from * rate) as usize..((from + 0.1) * rate) as usize].windows(2).filter(|w| w[0] < 0.0 && w[1] >= 0.0).count();
+        assert!((crossings(1.0) as i32 - 2 * crossings(0.0) as i32).abs() <= 1);
+    }
+}
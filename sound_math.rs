@@ -1,3 +1,31 @@
+mod analysis;
+mod block;
+mod effects;
+mod envelope;
+mod sampler;
+mod sequencer;
+mod signal;
+mod spectral;
+mod subtractive;
+mod tuning;
+
+use block::Block;
+use envelope::{Curve, Envelope, Retrigger, Segment};
+use sampler::Sampler;
+use sequencer::{Score, Sequencer, Voice};
+use signal::Signal;
+use subtractive::{Looping, Recipe, Subtractive};
+use tuning::Scale;
+use std::env;
+use std::f64::consts::PI;
+use std::fmt::Display;
+use std::fs;
+use std::path::Path;
+use std::process::ExitCode;
+use std::str::FromStr;
+
+const SAMPLE_RATE: u32 = 44100;
+
 fn smooth(t: f64, delay: f64, phase: f64, short: f64) -> f64 {
     let p = phase * PI;
     let a: f64 = (t / delay + p).tan().atan() * delay;
@@ -5,29 +33,25 @@ fn smooth(t: f64, delay: f64, phase: f64, short: f64) -> f64 {
     ((a * short).tanh() - b) / short
 }
 
-fn sharps(sm: f64, t: f64) -> f64 {
-   let mut y = 0_f64;
-
-   y += sharp(sm, t * (15.0 / 40.0)) / (15.0 / 40.0);
-   y += sharp(sm, t * (30.0 / 40.0)) / (30.0 / 40.0);
-   y += sharp(sm, t * (36.0 / 40.0)) / (36.0 / 40.0);
-   y += sharp(sm, t * (40.0 / 40.0)) / (40.0 / 40.0);
-   y += sharp(sm, t * (48.0 / 40.0)) / (48.0 / 40.0);
-   y += sharp(sm, t * (60.0 / 40.0)) / (60.0 / 40.0);
-   y += sharp(sm, t * (120.0 / 40.0)) / (120.0 / 40.0);
-
-   //y += sharp(sm, t * (12.0 / 48.0)) / (12.0 / 48.0) * 12.0;
-   //y += sharp(sm, t * (24.0 / 48.0)) / (24.0 / 48.0) * 24.0;
-   //y += sharp(sm, t * (48.0 / 48.0)) / (48.0 / 48.0) * 48.0;
+// The chord the `sharps` patch stacks, as degrees of its scale. In the
+// default just-minor scale these are the original 15/40, 30/40, 36/40, 40/40,
+// 48/40, 60/40 and 120/40. Each partial is weighted by the inverse of its
+// ratio (see `signal::sharp_stack`). Earlier experiments, from when the
+// ratios were written out by hand:
+//
+//   y += sharp(sm, t * (12.0 / 48.0)) / (12.0 / 48.0) * 12.0;
+//   y += sharp(sm, t * (24.0 / 48.0)) / (24.0 / 48.0) * 24.0;
+//   y += sharp(sm, t * (48.0 / 48.0)) / (48.0 / 48.0) * 48.0;
+//
+//   y += sharp(t * (30.0 / 48.0)) / (30.0 / 48.0) * 240.0;
+//   y += sharp(t * (36.0 / 48.0)) / (36.0 / 48.0) * 144.0;
+//
+//   y += sharp(t * (40.0 / 48.0)) / (40.0 / 48.0) * 40.0;
+//   y += sharp(sm, t * (4.0 / 48.0)) / (4.0 / 48.0) * 40.0;
+const SHARPS_DEGREES: [i32; 7] = [-10, -3, -1, 0, 2, 4, 11];
 
-   //y += sharp(t * (30.0 / 48.0)) / (30.0 / 48.0) * 240.0;
-   //y += sharp(t * (36.0 / 48.0)) / (36.0 / 48.0) * 144.0;
-
-   //y += sharp(t * (40.0 / 48.0)) / (40.0 / 48.0) * 40.0;
-   //y += sharp(sm, t * (4.0 / 48.0)) / (4.0 / 48.0) * 40.0;
-
-   y / 7.0
-}
+// The first two `pluck` gates; the rest repeat the second every 0.25 s.
+const PLUCK_GATES: [(f64, f64); 2] = [(0.0, 0.1), (0.25, 0.35)];
 
 #[inline(always)]
 fn nrm(t: f64) -> f64 {
@@ -97,19 +121,501 @@ fn sinc_osc_plus(t: f64, impulse_freq: f64, sinc_freq: f64, a: f64, b: f64) -> f
     sinc(t2 * m2 + b)
 }
 
-fn main() {
-    use rustfft::num_traits::FloatConst;
-    let spec = hound::WavSpec {
-        channels: 2,
-        sample_rate: SAMPLE_RATE,
-        bits_per_sample: 16,
-        sample_format: hound::SampleFormat::Int,
+// Peak limiter with instant attack and exponential release. Anything the gain
+// reduction misses is hard-clipped at the ceiling.
+struct Limiter {
+    ceiling: f64,
+    release: f64,
+    gain: f64,
+}
+
+impl Limiter {
+    fn new(ceiling: f64, release_secs: f64) -> Self {
+        let release = 1.0 - (-1.0 / (release_secs * SAMPLE_RATE as f64)).exp();
+        Self { ceiling, release, gain: 1.0 }
+    }
+
+    fn process(&mut self, x: f64) -> f64 {
+        let gain = self.track(x.abs());
+        (x * gain).clamp(-self.ceiling, self.ceiling)
+    }
+
+    // Updates the gain for a frame whose loudest channel is at `peak`, so
+    // stereo frames are limited together and the image doesn't shift.
+    fn track(&mut self, peak: f64) -> f64 {
+        let target = if peak * self.gain > self.ceiling { self.ceiling / peak } else { 1.0 };
+        if target < self.gain {
+            self.gain = target;
+        } else {
+            self.gain += (target - self.gain) * self.release;
+        }
+        self.gain
+    }
+}
+
+// Evaluates `osc` at every sample time of `duration` seconds, in order.
+fn render<S: Signal>(duration: f64, osc: S) -> Vec<f64> {
+    let frames = (duration * SAMPLE_RATE as f64).round() as usize;
+    (0..frames).map(|i| osc.at(i as f64 / SAMPLE_RATE as f64)).collect()
+}
+
+// Scales the peak to `ceiling` when `normalize` is set, then runs the limiter
+// so nothing leaves the range the WAV can hold. NaN/inf samples become silence.
+fn master(samples: &mut [f64], normalize: bool, ceiling: f64) {
+    for x in samples.iter_mut() {
+        if !x.is_finite() {
+            *x = 0.0;
+        }
+    }
+    let peak = samples.iter().fold(0_f64, |m, x| m.max(x.abs()));
+    if normalize && peak > 0.0 {
+        let k = ceiling / peak;
+        samples.iter_mut().for_each(|x| *x *= k);
+    }
+    let mut limiter = Limiter::new(ceiling, 0.05);
+    samples.iter_mut().for_each(|x| *x = limiter.process(*x));
+}
+
+const WAV_SPEC: hound::WavSpec = hound::WavSpec {
+    channels: 2,
+    sample_rate: SAMPLE_RATE,
+    bits_per_sample: 16,
+    sample_format: hound::SampleFormat::Int,
+};
+
+// `master` for a stereo pair: both channels share the normalisation and the
+// limiter gain.
+fn master_stereo(left: &mut [f64], right: &mut [f64], normalize: bool, ceiling: f64) {
+    for x in left.iter_mut().chain(right.iter_mut()) {
+        if !x.is_finite() {
+            *x = 0.0;
+        }
+    }
+    let peak = left.iter().chain(right.iter()).fold(0_f64, |m, x| m.max(x.abs()));
+    if normalize && peak > 0.0 {
+        let k = ceiling / peak;
+        left.iter_mut().chain(right.iter_mut()).for_each(|x| *x *= k);
+    }
+    let mut limiter = Limiter::new(ceiling, 0.05);
+    for (l, r) in left.iter_mut().zip(right.iter_mut()) {
+        let gain = limiter.track(l.abs().max(r.abs()));
+        *l = (*l * gain).clamp(-ceiling, ceiling);
+        *r = (*r * gain).clamp(-ceiling, ceiling);
+    }
+}
+
+// Mono goes to both channels.
+fn write_wav(path: &Path, samples: &[f64]) -> Result<(), hound::Error> {
+    write_wav_stereo(path, samples, samples)
+}
+
+fn write_wav_stereo(path: &Path, left: &[f64], right: &[f64]) -> Result<(), hound::Error> {
+    let mut writer = hound::WavWriter::create(path, WAV_SPEC)?;
+    for (l, r) in left.iter().zip(right) {
+        writer.write_sample((l * i16::MAX as f64) as i16)?;
+        writer.write_sample((r * i16::MAX as f64) as i16)?;
+    }
+    writer.finalize()
+}
+
+// Pulls `frames` frames from `source` in blocks of `block_size` and writes
+// them as they come, so nothing longer than one block is held in memory.
+fn write_wav_stream<B: Block>(path: &Path, source: &mut B, frames: usize, block_size: usize) -> Result<(), hound::Error> {
+    let mut writer = hound::WavWriter::create(path, WAV_SPEC)?;
+    let mut buf = vec![0_f32; block_size.max(1)];
+    let mut left = frames;
+    while left > 0 {
+        let block = &mut buf[..left.min(block_size.max(1))];
+        source.process(block);
+        for x in block.iter() {
+            let v = (*x as f64 * i16::MAX as f64) as i16;
+            writer.write_sample(v)?;
+            writer.write_sample(v)?;
+        }
+        left -= block.len();
+    }
+    writer.finalize()
+}
+
+// Named patches reachable from the command line.
+// Wraps the aliasing oscillators (`sharp`, `tri`, `sinc_osc`) in
+// `band_limited` when `oversample` is above 1; sines are left alone. Chords
+// are stacked from `scale`.
+fn patch(name: &str, oversample: usize, scale: &Scale) -> Option<Box<dyn Signal>> {
+    use signal::*;
+    let chord = scale.ratios(&SHARPS_DEGREES);
+    let bl = |osc: Box<dyn Signal>| if oversample > 1 { osc.band_limited(oversample).boxed() } else { osc };
+    Some(match name {
+        "osc" => osc.boxed(),
+        "tri" => bl(tri(220.0).boxed()),
+        "sharps" => bl(sharp_stack(0.99, 220.0, &chord).boxed()),
+        "sinc" => bl(sinc_osc(110.0, 880.0).boxed()).add(Const(-0.5)).boxed(),
+        "pulse" => bl(sharp_stack(0.95, 110.0, &chord).boxed()).gate(env(0.5, 8.0)).boxed(),
+        "vibrato" => bl(tri(220.0).fm(sine(5.0), 0.02).boxed()),
+        "rabbit" => bl(rabbit().speed(2.0 * PI * 110.0).boxed()).add(Const(-0.5)).mul(smooth(0.25, 0.0, 4.0)).boxed(),
+        "bells" => bl(trim(sinc_osc_plus(2.0, 660.0, 0.3, 0.0)).pm(sine(3.0), 0.0005).boxed()).add(Const(-0.5)).boxed(),
+        // four plucks a second, each opening up the sharpness as well as the level.
+        // Every pluck after the first starts from the same level (the attack
+        // reaches 1 whatever it starts from), so the envelopes only need the
+        // first two gates and a clock folded onto the second.
+        "pluck" => {
+            let level = Envelope::adsr(0.005, 0.15, 0.3, 0.2).trigger(&PLUCK_GATES, Retrigger::Legato);
+            let bright = Envelope::breakpoints(0.99, vec![Segment::new(0.2, 0.5, Curve::Smooth)]).trigger(&PLUCK_GATES, Retrigger::Reset);
+            let fold = |t: f64| if t < 0.25 { t } else { 0.25 + t % 0.25 };
+            let level = move |t: f64| level.at(fold(t));
+            bl((move |t: f64| crate::sharp(bright.at(fold(t)), t * 220.0 * 2.0 * PI)).boxed()).mul(level).boxed()
+        }
+        // harmonics of 110 Hz weighted by the sub01 curve centred on 440 Hz
+        "sub01" => {
+            let curve = sub01(440.0, 1.0, 1.0, 1.0);
+            (1..=16).fold(Mix::new(), |mix, k| mix.with(1.0, sine(110.0 * k as f64).gain(curve.at(110.0 * k as f64)))).boxed()
+        }
+        _ => return None,
+    })
+}
+
+const USAGE: &str = "usage: sound_math [patch] [seconds] [out.wav] [oversample] [scale] [fx]
+       sound_math play <score.mid|score.txt> [voice] [out.wav] [oversample] [scale] [adsr]
+       sound_math analyze [patch] [seconds] [out_prefix] [oversample] [scale]
+       sound_math stream [patch] [seconds] [out.wav] [block]
+       sound_math sample <presets.json> <score.mid|score.txt> [out.wav] [preset] [crossfade] [jitter] [adsr]
+       sound_math noise <presets.json> [count] [seconds] [looping] [filters] [window] [wav_prefix]";
+
+const PATCHES: &str = "osc, tri, sharps, sinc, pulse, vibrato, rabbit, bells, pluck or sub01";
+
+fn parse<T: FromStr>(name: &str, value: &str) -> Result<T, String>
+where
+    T::Err: Display,
+{
+    value.parse::<T>().map_err(|e| format!("invalid {name} '{value}': {e}"))
+}
+
+// Positional argument `i`, falling back to `default` when it is absent.
+fn arg<T: FromStr>(args: &[String], i: usize, name: &str, default: T) -> Result<T, String>
+where
+    T::Err: Display,
+{
+    match args.get(i) {
+        Some(value) => parse(name, value),
+        None => Ok(default),
+    }
+}
+
+fn find_patch(name: &str, oversample: usize, scale: &Scale) -> Result<Box<dyn Signal>, String> {
+    patch(name, oversample, scale).ok_or_else(|| format!("unknown patch '{name}', expected {PATCHES}"))
+}
+
+fn find_scale(name: &str) -> Result<Scale, String> {
+    Scale::lookup(name).map_err(|e| e.to_string())
+}
+
+// `attack,decay,sustain,release`, as `play` and `sample` take it.
+fn adsr(spec: &str) -> Result<Envelope, String> {
+    let values = spec.split(',').map(|x| parse::<f64>("adsr", x)).collect::<Result<Vec<_>, _>>()?;
+    match values[..] {
+        [a, d, s, r] => Ok(Envelope::adsr(a, d, s, r)),
+        _ => Err(format!("expected adsr as attack,decay,sustain,release, got '{spec}'")),
+    }
+}
+
+// Makes the directory `path` goes in, so the default `./tmp` outputs work on
+// a fresh checkout.
+fn create_parent(path: &Path) -> Result<(), String> {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => fs::create_dir_all(dir).map_err(|e| format!("cannot create {}: {e}", dir.display())),
+        _ => Ok(()),
+    }
+}
+
+fn run() -> Result<(), String> {
+    let args: Vec<String> = env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        Some("play") => play(&args[1..]),
+        Some("analyze") => analyze(&args[1..]),
+        Some("stream") => stream(&args[1..]),
+        Some("sample") => sample(&args[1..]),
+        Some("noise") => noise(&args[1..]),
+        _ => render_patch(&args),
+    }
+}
+
+fn main() -> ExitCode {
+    match run() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("sound_math: {e}");
+            eprintln!("{USAGE}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+// `sound_math [patch] [seconds] [out.wav] [oversample] [scale] [fx]`
+fn render_patch(args: &[String]) -> Result<(), String> {
+    let name = args.first().map_or("sharps", String::as_str);
+    let duration = arg(args, 1, "seconds", 2.0)?;
+    let out = args.get(2).cloned().unwrap_or_else(|| format!("./tmp/{name}.wav"));
+    let oversample = arg(args, 3, "oversample", 1)?;
+    let scale = find_scale(args.get(4).map_or("just-minor", String::as_str))?;
+
+    let osc = find_patch(name, oversample, &scale)?;
+    let path = Path::new(&out);
+    create_parent(path)?;
+    match args.get(5) {
+        None => {
+            let mut samples = render(duration, osc);
+            master(&mut samples, true, 0.89);
+            write_wav(path, &samples).map_err(|e| format!("cannot write {out}: {e}"))?;
+            println!("Wrote {} samples to {}", samples.len(), out);
+        }
+        // an effects chain makes the render stereo
+        Some(spec) => {
+            let fx = effects::parse_chain(spec)?;
+            let mut chain = effects::Chain::new(block::Stream::new(osc)).with(fx);
+            let (left, right) = chain.render((duration * SAMPLE_RATE as f64).round() as usize);
+            let mut left: Vec<f64> = left.iter().map(|&x| x as f64).collect();
+            let mut right: Vec<f64> = right.iter().map(|&x| x as f64).collect();
+            master_stereo(&mut left, &mut right, true, 0.89);
+            write_wav_stereo(path, &left, &right).map_err(|e| format!("cannot write {out}: {e}"))?;
+            println!("Wrote {} stereo samples to {}", left.len(), out);
+        }
+    }
+    Ok(())
+}
+
+// `sound_math stream <patch> [seconds] [out.wav] [block]` renders block by
+// block straight to disk, limited but not normalised, for pieces too long to
+// hold in memory.
+fn stream(args: &[String]) -> Result<(), String> {
+    let name = args.first().map_or("sharps", String::as_str);
+    let duration = arg(args, 1, "seconds", 2.0)?;
+    let out = args.get(2).cloned().unwrap_or_else(|| format!("./tmp/{name}.wav"));
+    let block_size = arg(args, 3, "block", 1024)?;
+
+    // the single-oscillator patches have phase-accumulating versions, and the
+    // bare oscillators behind `sinc` and `rabbit` can be streamed on their own
+    let source: Box<dyn Block> = match name {
+        "osc" => Box::new(block::sine(440.0)),
+        "tri" => Box::new(block::tri(220.0)),
+        "sharp" => Box::new(block::sharp(0.99, 220.0)),
+        "sinc-osc" => Box::new(block::sinc_osc(110.0, 880.0)),
+        "rabbit-osc" => Box::new(block::rabbit(110.0)),
+        _ => Box::new(block::Stream::new(find_patch(name, 1, &find_scale("just-minor")?)?)),
     };
-    let mut rng = rand::thread_rng();
+    let mut source = block::Limited::new(source, 0.89);
+    let frames = (duration * SAMPLE_RATE as f64).round() as usize;
+    let path = Path::new(&out);
+    create_parent(path)?;
+    write_wav_stream(path, &mut source, frames, block_size).map_err(|e| format!("cannot write {out}: {e}"))?;
+    println!("Streamed {} samples to {} in blocks of {}", frames, out, block_size);
+    Ok(())
+}
+
+// `sound_math analyze <patch> [seconds] [out_prefix] [oversample] [scale]`
+// renders a patch like `main` and writes `{out_prefix}_spectrogram.png` and
+// `{out_prefix}_waveform.png` instead of a WAV.
+fn analyze(args: &[String]) -> Result<(), String> {
+    let name = args.first().map_or("sharps", String::as_str);
+    let duration = arg(args, 1, "seconds", 2.0)?;
+    let prefix = args.get(2).cloned().unwrap_or_else(|| format!("./tmp/{name}"));
+    let oversample = arg(args, 3, "oversample", 1)?;
+    let scale = find_scale(args.get(4).map_or("just-minor", String::as_str))?;
+
+    let osc = find_patch(name, oversample, &scale)?;
+    let mut samples = render(duration, osc);
+    master(&mut samples, true, 0.89);
+    let spectrogram_path = format!("{prefix}_spectrogram.png");
+    let waveform_path = format!("{prefix}_waveform.png");
+    create_parent(Path::new(&spectrogram_path))?;
+    let spectrogram = analysis::Spectrogram::default().render(&samples);
+    spectrogram.save(&spectrogram_path).map_err(|e| format!("cannot write {spectrogram_path}: {e}"))?;
+    analysis::waveform(&samples, 1600, 400).save(&waveform_path).map_err(|e| format!("cannot write {waveform_path}: {e}"))?;
+    println!("Wrote {spectrogram_path} ({}x{}) and {waveform_path}", spectrogram.width(), spectrogram.height());
+    Ok(())
+}
+
+// `sound_math play <score> [voice] [out.wav] [oversample] [scale] [adsr]`
+// renders a MIDI file or text score (see `sequencer::Score`). `adsr` is
+// `attack,decay,sustain,release`; without it notes use `env`.
+fn play(args: &[String]) -> Result<(), String> {
+    let path = Path::new(args.first().ok_or("play needs a score")?);
+    let voice = arg(args, 1, "voice", Voice::Sine)?;
+    let stem = path.file_stem().map_or("score".into(), |s| s.to_string_lossy());
+    let out = args.get(2).cloned().unwrap_or_else(|| format!("./tmp/{stem}.wav"));
+    let scale = find_scale(args.get(4).map_or("12-tet", String::as_str))?;
+
+    let score = Score::load(path).map_err(|e| format!("cannot load {}: {e}", path.display()))?;
+    let mut sequencer = Sequencer::new(voice, scale);
+    sequencer.oversample = arg(args, 3, "oversample", 1)?;
+    sequencer.envelope = args.get(5).map(|spec| adsr(spec)).transpose()?;
+    let mut samples = sequencer.render(&score);
+    master(&mut samples, true, 0.89);
+    let out_path = Path::new(&out);
+    create_parent(out_path)?;
+    write_wav(out_path, &samples).map_err(|e| format!("cannot write {out}: {e}"))?;
+    println!("Wrote {} notes, {} samples to {}", score.notes.len(), samples.len(), out);
+    Ok(())
+}
+
+// `sound_math sample <presets.json> <score> [out.wav] [preset] [crossfade] [jitter] [adsr]`
+// plays a score on a saved `Subtractive` preset (see `sampler::Sampler`).
+// `crossfade` is in seconds, and `none` plays the preset once per note instead
+// of looping it; `jitter` (0 to 1) randomises where each note starts.
+fn sample(args: &[String]) -> Result<(), String> {
+    let presets_path = Path::new(args.first().ok_or("sample needs a preset file")?);
+    let presets = Subtractive::load(presets_path).map_err(|e| format!("cannot load {}: {e}", presets_path.display()))?;
+    let path = Path::new(args.get(1).ok_or("sample needs a score")?);
+    let stem = path.file_stem().map_or("score".into(), |s| s.to_string_lossy());
+    let out = args.get(2).cloned().unwrap_or_else(|| format!("./tmp/{stem}_sampled.wav"));
+    let index = arg(args, 3, "preset", 0)?;
+    let crossfade = match args.get(4).map(String::as_str) {
+        Some("none") => None,
+        _ => Some((arg(args, 4, "crossfade", 0.05)? * SAMPLE_RATE as f64).round() as usize),
+    };
+    let preset = presets.preset.get(index).ok_or_else(|| format!("no preset {index}, the file has {}", presets.preset.len()))?;
+    let mut sampler = Sampler::from_preset(preset, crossfade);
+    sampler.jitter = arg(args, 5, "jitter", 0.0)?;
+
+    let score = Score::load(path).map_err(|e| format!("cannot load {}: {e}", path.display()))?;
+    let mut sequencer = Sequencer::new(Voice::Sine, find_scale("12-tet")?);
+    sequencer.envelope = args.get(6).map(|spec| adsr(spec)).transpose()?;
+    let (mut left, mut right) = sequencer.render_stereo(&score, |freq, i| Box::new(sampler.voice(freq, i)));
+    master_stereo(&mut left, &mut right, true, 0.89);
+    let out_path = Path::new(&out);
+    create_parent(out_path)?;
+    write_wav_stereo(out_path, &left, &right).map_err(|e| format!("cannot write {out}: {e}"))?;
+    println!("Wrote {} notes on preset {}, {} stereo samples to {}", score.notes.len(), index, left.len(), out);
+    Ok(())
+}
+
+// `sound_math noise <presets.json> [count] [seconds] [looping] [filters] [window] [wav_prefix]`
+// makes a bank of `Subtractive` presets for `sample`, one per seed from 0, all
+// shaped by the same filters (see `spectral::parse_stages`) and window, and
+// saves their recipes. With `wav_prefix` each preset is also written to
+// `{wav_prefix}_{i}.wav` to listen to.
+fn noise(args: &[String]) -> Result<(), String> {
+    let out = Path::new(args.first().ok_or("noise needs an output file")?);
+    let count = arg(args, 1, "count", 8)?;
+    let seconds = arg(args, 2, "seconds", 1.0)?;
+    let looping = arg(args, 3, "looping", Looping::Seamless)?;
+    let stages = spectral::parse_stages(args.get(4).map_or("", String::as_str))?;
+    let window = match args.get(5).map(String::as_str) {
+        None | Some("none") => None,
+        Some(shape) => Some(parse("window", shape)?),
+    };
+
+    let mut sub = Subtractive::with_len((seconds * SAMPLE_RATE as f64).round() as usize, looping);
+    let recipes = (0..count as u64).map(|seed| Recipe { window, left: stages.clone(), ..Recipe::new(seed, sub.len, sub.looping) }).collect();
+    sub.add_all(recipes);
+    create_parent(out)?;
+    sub.save(out, false).map_err(|e| format!("cannot write {}: {e}", out.display()))?;
+    println!("Wrote {} presets of {} samples to {}", count, sub.len, out.display());
+
+    if let Some(prefix) = args.get(6) {
+        for i in 0..count {
+            let path = format!("{prefix}_{i}.wav");
+            create_parent(Path::new(&path))?;
+            sub.export_wav(i, Path::new(&path)).map_err(|e| format!("cannot write {path}: {e}"))?;
+        }
+        println!("Wrote {} WAVs to {}_*.wav", count, prefix);
+    }
+    Ok(())
 }
 
 #[inline(always)]
 fn osc(t: f64) -> f64 {
-    let sample = (t * 440.0 * 2.0 * PI).sin();
-    sample
+    (t * 440.0 * 2.0 * PI).sin()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_length_and_values() {
+        let samples = render(0.5, osc);
+        assert_eq!(samples.len(), SAMPLE_RATE as usize / 2);
+        assert_eq!(samples[0], 0.0);
+        assert_eq!(samples[100], osc(100.0 / SAMPLE_RATE as f64));
+    }
+
+    #[test]
+    fn test_master_holds_ceiling() {
+        let mut loud = render(0.2, |t| osc(t) * 3.0);
+        loud[100] = f64::NAN;
+        master(&mut loud, false, 0.5);
+        assert!(loud.iter().all(|x| x.abs() <= 0.5));
+        assert_eq!(loud[100], 0.0);
+
+        let mut quiet = render(0.2, |t| osc(t) * 0.1);
+        master(&mut quiet, true, 0.8);
+        let peak = quiet.iter().fold(0_f64, |m, x| m.max(x.abs()));
+        assert!((peak - 0.8).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_stereo_master_is_linked() {
+        let mut left: Vec<f64> = (0..4410).map(|i| 3.0 * (i as f64 * 0.05).sin()).collect();
+        let mut right: Vec<f64> = left.iter().map(|x| x * 0.5).collect();
+        right[10] = f64::NAN;
+        master_stereo(&mut left, &mut right, false, 0.8);
+        assert!(left.iter().chain(&right).all(|x| x.abs() <= 0.8));
+        assert_eq!(right[10], 0.0);
+        // the limiter pulls both channels down by the same gain (except where
+        // the louder one is clipped as the gain recovers)
+        assert!(left.iter().zip(&right).enumerate().all(|(i, (l, r))| i == 10 || l.abs() >= 0.8 || (l * 0.5 - r).abs() < 1e-12));
+    }
+
+    #[test]
+    fn test_pluck_keeps_plucking() {
+        // folding onto the second gate gives the same envelope as the full gate list
+        let gates: Vec<(f64, f64)> = (0..64).map(|k| (k as f64 * 0.25, k as f64 * 0.25 + 0.1)).collect();
+        let full = Envelope::adsr(0.005, 0.15, 0.3, 0.2).trigger(&gates, Retrigger::Legato);
+        let folded = Envelope::adsr(0.005, 0.15, 0.3, 0.2).trigger(&PLUCK_GATES, Retrigger::Legato);
+        for t in (0..1600).map(|i| i as f64 * 0.01) {
+            let f = if t < 0.25 { t } else { 0.25 + t % 0.25 };
+            assert!((full.at(t) - folded.at(f)).abs() < 1e-9, "{t}");
+        }
+
+        // and the patch is still sounding long after any fixed list would run out
+        let scale = Scale::named("just-minor").unwrap();
+        let pluck = patch("pluck", 1, &scale).unwrap();
+        let late = (0..2205).map(|i| pluck.at(1000.0 + i as f64 / SAMPLE_RATE as f64).powi(2)).sum::<f64>();
+        let early = (0..2205).map(|i| pluck.at(1.0 + i as f64 / SAMPLE_RATE as f64).powi(2)).sum::<f64>();
+        assert!((late / early - 1.0).abs() < 1e-3, "{late} {early}");
+    }
+
+    #[test]
+    fn test_streamed_wav_matches_blocks() {
+        let path = std::env::temp_dir().join(format!("sound_math_stream_{}.wav", std::process::id()));
+        let mut source = block::Limited::new(block::Stream::new(signal::sine(440.0).gain(2.0)), 0.89);
+        write_wav_stream(&path, &mut source, 3000, 256).unwrap();
+        let read: Vec<i16> = hound::WavReader::open(&path).unwrap().samples::<i16>().map(Result::unwrap).collect();
+        assert_eq!(read.len(), 6000);
+
+        let mut again = block::Limited::new(block::Stream::new(signal::sine(440.0).gain(2.0)), 0.89);
+        let mut buf = vec![0_f32; 3000];
+        again.process(&mut buf);
+        assert!(read.chunks(2).zip(&buf).all(|(lr, x)| lr[0] == lr[1] && lr[0] == (*x as f64 * i16::MAX as f64) as i16));
+    }
+
+    #[test]
+    fn test_patches_render_clean_wavs() {
+        let dir = std::env::temp_dir().join(format!("sound_math_test_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let scale = Scale::named("just-minor").unwrap();
+        for (name, oversample) in ["osc", "tri", "sharps", "sinc", "pulse", "vibrato", "rabbit", "bells", "pluck", "sub01"].iter().flat_map(|n| [(*n, 1), (*n, 2)]) {
+            let mut samples = render(0.1, patch(name, oversample, &scale).unwrap());
+            master(&mut samples, true, 0.89);
+            assert!(samples.iter().all(|x| x.is_finite() && x.abs() <= 0.89), "{name}");
+
+            let path = dir.join(format!("{name}_{oversample}.wav"));
+            write_wav(&path, &samples).unwrap();
+            let mut reader = hound::WavReader::open(&path).unwrap();
+            assert_eq!(reader.spec().channels, 2);
+            let read: Vec<i16> = reader.samples::<i16>().map(Result::unwrap).collect();
+            assert_eq!(read.len(), samples.len() * 2);
+            assert_eq!(read[2], (samples[1] * i16::MAX as f64) as i16);
+        }
+    }
 }
@@ -0,0 +1,480 @@
+use rustfft::num_complex::Complex;
+use serde::{Deserialize, Serialize};
+use std::f64::consts::PI;
+use std::str::FromStr;
+
+use super::SAMPLE_RATE;
+
+// A zero-phase magnitude response over frequency, for the `f` stage of
+// `Subtractive::new_preset`. Any `Fn(f64) -> f64` of hertz is a filter.
+pub trait Filter {
+    fn gain(&self, hz: f64) -> f64;
+
+    // in series: the gains multiply (for filters built in code; the CLI's
+    // `parse_stages` lists already run in series)
+    #[allow(dead_code)]
+    fn then<F: Filter>(self, next: F) -> Cascade<Self, F>
+    where
+        Self: Sized,
+    {
+        Cascade(self, next)
+    }
+
+    // in parallel: the gains add
+    #[allow(dead_code)]
+    fn plus<F: Filter>(self, other: F) -> Parallel<Self, F>
+    where
+        Self: Sized,
+    {
+        Parallel(self, other)
+    }
+}
+
+impl<F: Fn(f64) -> f64> Filter for F {
+    fn gain(&self, hz: f64) -> f64 {
+        self(hz)
+    }
+}
+
+pub struct Cascade<A, B>(A, B);
+
+impl<A: Filter, B: Filter> Filter for Cascade<A, B> {
+    fn gain(&self, hz: f64) -> f64 {
+        self.0.gain(hz) * self.1.gain(hz)
+    }
+}
+
+pub struct Parallel<A, B>(A, B);
+
+impl<A: Filter, B: Filter> Filter for Parallel<A, B> {
+    fn gain(&self, hz: f64) -> f64 {
+        self.0.gain(hz) + self.1.gain(hz)
+    }
+}
+
+// The `f` closure for `new_preset`. Bins above Nyquist are the mirror images
+// of those below, so they get the same gain and the preset stays real.
+pub fn bins<F: Filter>(filter: F) -> impl Fn(&mut Complex<f64>, f64, f64) {
+    move |bin, _, hz| *bin *= filter.gain(fold(hz))
+}
+
+// Like `bins` with a different filter per channel.
+pub fn stereo<L: Filter, R: Filter>(left: L, right: R) -> impl Fn(&mut Complex<f64>, f64, f64) {
+    move |bin, side, hz| *bin *= if side < 0.5 { left.gain(fold(hz)) } else { right.gain(fold(hz)) }
+}
+
+fn fold(hz: f64) -> f64 {
+    let rate = SAMPLE_RATE as f64;
+    if hz > rate / 2.0 {
+        rate - hz
+    } else {
+        hz
+    }
+}
+
+// Second-order (12 dB/octave) responses. `q` is the resonance: the gain at the
+// cutoff of the low- and high-pass, and the sharpness of the band-pass peak.
+// 0.707 is flat (Butterworth).
+pub struct LowPass {
+    pub cutoff: f64,
+    pub q: f64,
+}
+
+pub struct HighPass {
+    pub cutoff: f64,
+    pub q: f64,
+}
+
+// Peaks at 1 on `center`.
+pub struct BandPass {
+    pub center: f64,
+    pub q: f64,
+}
+
+fn resonance(r: f64, q: f64) -> f64 {
+    ((1.0 - r * r).powi(2) + (r / q).powi(2)).sqrt()
+}
+
+impl Filter for LowPass {
+    fn gain(&self, hz: f64) -> f64 {
+        1.0 / resonance(hz / self.cutoff, self.q)
+    }
+}
+
+impl Filter for HighPass {
+    fn gain(&self, hz: f64) -> f64 {
+        let r = hz / self.cutoff;
+        r * r / resonance(r, self.q)
+    }
+}
+
+impl Filter for BandPass {
+    fn gain(&self, hz: f64) -> f64 {
+        let r = hz / self.center;
+        r / self.q / resonance(r, self.q)
+    }
+}
+
+// Feedback comb: peaks of 1 at every multiple of `freq`, with troughs that
+// deepen as `feedback` approaches 1.
+pub struct Comb {
+    pub freq: f64,
+    pub feedback: f64,
+}
+
+impl Filter for Comb {
+    fn gain(&self, hz: f64) -> f64 {
+        let g = self.feedback.clamp(0.0, 0.999);
+        (1.0 - g) / (1.0 - 2.0 * g * (2.0 * PI * hz / self.freq).cos() + g * g).sqrt()
+    }
+}
+
+// Three band-pass peaks with falling levels.
+pub struct Formant {
+    pub peaks: [(f64, f64); 3],
+}
+
+impl Formant {
+    // First three formants of an adult male voice (Peterson & Barney).
+    pub fn vowel(vowel: char) -> Option<Formant> {
+        let [f1, f2, f3] = match vowel {
+            'a' => [730.0, 1090.0, 2440.0],
+            'e' => [530.0, 1840.0, 2480.0],
+            'i' => [270.0, 2290.0, 3010.0],
+            'o' => [570.0, 840.0, 2410.0],
+            'u' => [300.0, 870.0, 2240.0],
+            _ => return None,
+        };
+        Some(Formant { peaks: [(f1, 1.0), (f2, 0.5), (f3, 0.25)] })
+    }
+}
+
+impl Filter for Formant {
+    fn gain(&self, hz: f64) -> f64 {
+        // about 80 to 120 Hz wide, like the voice
+        self.peaks.iter().map(|&(center, level)| level * BandPass { center, q: center / 100.0 }.gain(hz)).sum()
+    }
+}
+
+// Constant slope in dB per octave, 0 dB at `reference`. Frequencies below
+// 20 Hz are treated as 20 Hz so DC doesn't blow up.
+pub struct Tilt {
+    pub db_per_octave: f64,
+    pub reference: f64,
+}
+
+impl Tilt {
+    pub fn pink() -> Tilt {
+        Tilt { db_per_octave: -3.0, reference: 1000.0 }
+    }
+
+    pub fn brown() -> Tilt {
+        Tilt { db_per_octave: -6.0, reference: 1000.0 }
+    }
+}
+
+impl Filter for Tilt {
+    fn gain(&self, hz: f64) -> f64 {
+        10_f64.powf(self.db_per_octave / 20.0 * (hz.max(20.0) / self.reference).log2())
+    }
+}
+
+// Notches (gain 0 at each frequency) of width set by `q`.
+pub struct Notches {
+    pub freqs: Vec<f64>,
+    pub q: f64,
+}
+
+impl Notches {
+    // Removes the first `count` harmonics of `fundamental`, e.g. to hollow out hum.
+    pub fn harmonic(fundamental: f64, count: usize, q: f64) -> Notches {
+        Notches { freqs: (1..=count).map(|k| fundamental * k as f64).collect(), q }
+    }
+}
+
+impl Filter for Notches {
+    fn gain(&self, hz: f64) -> f64 {
+        self.freqs
+            .iter()
+            .map(|&f| {
+                let r = hz / f;
+                (1.0 - r * r).abs() / resonance(r, self.q)
+            })
+            .product()
+    }
+}
+
+// The filters above as plain data, so a preset's recipe can be saved and
+// rebuilt (see `subtractive::Recipe`). A list of stages runs in series.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Stage {
+    LowPass { cutoff: f64, q: f64 },
+    HighPass { cutoff: f64, q: f64 },
+    BandPass { center: f64, q: f64 },
+    Comb { freq: f64, feedback: f64 },
+    Vowel { vowel: char },
+    Tilt { db_per_octave: f64, reference: f64 },
+    Notches { freqs: Vec<f64>, q: f64 },
+}
+
+impl Filter for Stage {
+    fn gain(&self, hz: f64) -> f64 {
+        match self {
+            Stage::LowPass { cutoff, q } => LowPass { cutoff: *cutoff, q: *q }.gain(hz),
+            Stage::HighPass { cutoff, q } => HighPass { cutoff: *cutoff, q: *q }.gain(hz),
+            Stage::BandPass { center, q } => BandPass { center: *center, q: *q }.gain(hz),
+            Stage::Comb { freq, feedback } => Comb { freq: *freq, feedback: *feedback }.gain(hz),
+            // unknown vowels pass everything
+            Stage::Vowel { vowel } => Formant::vowel(*vowel).map_or(1.0, |f| f.gain(hz)),
+            Stage::Tilt { db_per_octave, reference } => Tilt { db_per_octave: *db_per_octave, reference: *reference }.gain(hz),
+            Stage::Notches { freqs, q } => Notches { freqs: freqs.clone(), q: *q }.gain(hz),
+        }
+    }
+}
+
+impl Filter for Vec<Stage> {
+    fn gain(&self, hz: f64) -> f64 {
+        self.iter().map(|stage| stage.gain(hz)).product()
+    }
+}
+
+impl From<Tilt> for Stage {
+    fn from(tilt: Tilt) -> Stage {
+        Stage::Tilt { db_per_octave: tilt.db_per_octave, reference: tilt.reference }
+    }
+}
+
+impl From<Notches> for Stage {
+    fn from(notches: Notches) -> Stage {
+        Stage::Notches { freqs: notches.freqs, q: notches.q }
+    }
+}
+
+// Parses stages like `lowpass:800:0.7,vowel:o,pink`:
+//   lowpass:CUTOFF:Q  highpass:CUTOFF:Q  bandpass:CENTER:Q
+//   comb:FREQ:FEEDBACK
+//   vowel:a|e|i|o|u
+//   tilt:DB_PER_OCTAVE[:REFERENCE]     0 dB at 1 kHz by default
+//   pink  brown                        -3 and -6 dB/octave tilts
+//   notch:FUNDAMENTAL:COUNT:Q          the first COUNT harmonics, e.g. to hollow out hum
+// The most notches a `notch` stage may ask for; a typo shouldn't allocate a
+// huge filter.
+pub const MAX_NOTCHES: usize = 64;
+
+pub fn parse_stages(spec: &str) -> Result<Vec<Stage>, String> {
+    spec.split(',')
+        .filter(|s| !s.is_empty())
+        .map(|node| {
+            let fields: Vec<&str> = node.split(':').collect();
+            let num = |i: usize| {
+                fields
+                    .get(i)
+                    .and_then(|f| f.parse::<f64>().ok())
+                    .filter(|v| v.is_finite())
+                    .ok_or_else(|| format!("bad or missing value {} in '{}'", i, node))
+            };
+            // frequencies and q must be above zero
+            let pos = |i: usize| num(i).and_then(|v| if v > 0.0 { Ok(v) } else { Err(format!("value {} in '{}' must be positive", i, node)) });
+            Ok(match fields[0] {
+                "lowpass" => Stage::LowPass { cutoff: pos(1)?, q: pos(2)? },
+                "highpass" => Stage::HighPass { cutoff: pos(1)?, q: pos(2)? },
+                "bandpass" => Stage::BandPass { center: pos(1)?, q: pos(2)? },
+                "comb" => Stage::Comb { freq: pos(1)?, feedback: num(2)? },
+                "vowel" => match fields.get(1).and_then(|v| v.parse::<char>().ok()) {
+                    Some(vowel) if Formant::vowel(vowel).is_some() => Stage::Vowel { vowel },
+                    _ => return Err(format!("expected a vowel a, e, i, o or u in '{}'", node)),
+                },
+                "tilt" => Stage::Tilt { db_per_octave: num(1)?, reference: if fields.len() > 2 { pos(2)? } else { 1000.0 } },
+                "pink" => Tilt::pink().into(),
+                "brown" => Tilt::brown().into(),
+                "notch" => {
+                    let count = fields.get(2).and_then(|f| f.parse::<usize>().ok()).filter(|n| (1..=MAX_NOTCHES).contains(n));
+                    let count = count.ok_or_else(|| format!("expected 1 to {} notches in '{}'", MAX_NOTCHES, node))?;
+                    Notches::harmonic(pos(1)?, count, pos(3)?).into()
+                }
+                other => {
+                    return Err(format!(
+                        "unknown filter '{}', expected lowpass, highpass, bandpass, comb, vowel, tilt, pink, brown or notch",
+                        other
+                    ))
+                }
+            })
+        })
+        .collect()
+}
+
+// Time-domain shapes for the `g` stage of `new_preset`, over the preset from
+// start (0) to end (1).
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Window {
+    Hann,
+    Hamming,
+    Blackman,
+    // flat in the middle with Hann fades over `alpha` of the length in total
+    Tukey(f64),
+    // bell centred in the preset, `sigma` as a fraction of the length
+    Gaussian(f64),
+    // e^(-rate * position): a struck, decaying sound
+    Decay(f64),
+}
+
+impl Window {
+    pub fn gain(self, pos: f64) -> f64 {
+        let pos = pos.clamp(0.0, 1.0);
+        let c = |k: f64| (2.0 * PI * k * pos).cos();
+        match self {
+            Window::Hann => 0.5 - 0.5 * c(1.0),
+            Window::Hamming => 0.54 - 0.46 * c(1.0),
+            Window::Blackman => 0.42 - 0.5 * c(1.0) + 0.08 * c(2.0),
+            Window::Tukey(alpha) => {
+                let edge = alpha.clamp(1e-9, 1.0) / 2.0;
+                let d = pos.min(1.0 - pos);
+                if d >= edge {
+                    1.0
+                } else {
+                    0.5 - 0.5 * (PI * d / edge).cos()
+                }
+            }
+            Window::Gaussian(sigma) => (-0.5 * ((pos - 0.5) / sigma).powi(2)).exp(),
+            Window::Decay(rate) => (-rate * pos).exp(),
+        }
+    }
+}
+
+// `hann`, `hamming`, `blackman`, `tukey:ALPHA`, `gaussian:SIGMA` or
+// `decay:RATE`.
+impl FromStr for Window {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Window, String> {
+        let (name, value) = s.split_once(':').unwrap_or((s, ""));
+        let num = || value.parse::<f64>().ok().filter(|v| v.is_finite()).ok_or_else(|| format!("bad or missing value in window '{}'", s));
+        // a zero-width tukey fade or gaussian bell is silence or a divide by zero
+        let pos = || num().and_then(|v| if v > 0.0 { Ok(v) } else { Err(format!("window '{}' needs a positive value", s)) });
+        match name {
+            "hann" => Ok(Window::Hann),
+            "hamming" => Ok(Window::Hamming),
+            "blackman" => Ok(Window::Blackman),
+            "tukey" => Ok(Window::Tukey(pos()?)),
+            "gaussian" => Ok(Window::Gaussian(pos()?)),
+            "decay" => Ok(Window::Decay(num()?)),
+            _ => Err(format!("unknown window '{}', expected hann, hamming, blackman, tukey, gaussian or decay", s)),
+        }
+    }
+}
+
+// The `g` closure for `new_preset`. Its `x` runs from just under 0 to just
+// under 2 across the preset.
+pub fn window(shape: Window) -> impl Fn(&mut Complex<f64>, f64, f64) {
+    move |sample, _, x| *sample *= shape.gain(x / 2.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::subtractive::{Looping, Subtractive};
+
+    fn close(a: f64, b: f64, tolerance: f64) -> bool {
+        (a - b).abs() < tolerance
+    }
+
+    #[test]
+    fn test_pass_filters() {
+        let low = LowPass { cutoff: 1000.0, q: 4.0 };
+        assert!(close(low.gain(0.0), 1.0, 1e-12));
+        assert!(close(low.gain(1000.0), 4.0, 1e-12));
+        // 12 dB/octave
+        assert!(close(low.gain(10_000.0), 0.0101, 1e-3));
+
+        let high = HighPass { cutoff: 1000.0, q: 0.707 };
+        assert_eq!(high.gain(0.0), 0.0);
+        assert!(close(high.gain(1000.0), 0.707, 1e-12));
+        assert!(close(high.gain(20_000.0), 1.0, 1e-2));
+
+        let band = BandPass { center: 500.0, q: 10.0 };
+        assert!(close(band.gain(500.0), 1.0, 1e-12));
+        assert!(band.gain(400.0) < 0.25 && band.gain(650.0) < 0.25);
+    }
+
+    #[test]
+    fn test_shaping_filters() {
+        let comb = Comb { freq: 100.0, feedback: 0.9 };
+        assert!(close(comb.gain(300.0), 1.0, 1e-12));
+        assert!(comb.gain(350.0) < 0.06);
+
+        let ah = Formant::vowel('a').unwrap();
+        assert!(ah.gain(730.0) > 0.9 && ah.gain(1090.0) > 0.45 && ah.gain(1500.0) < 0.2);
+        assert!(Formant::vowel('y').is_none());
+
+        let pink = Tilt::pink();
+        assert!(close(pink.gain(1000.0), 1.0, 1e-12));
+        assert!(close(20.0 * pink.gain(2000.0).log10(), -3.0, 1e-9));
+        assert!(close(20.0 * Tilt::brown().gain(250.0).log10(), 12.0, 1e-9));
+        assert_eq!(pink.gain(0.0), pink.gain(20.0));
+
+        let hum = Notches::harmonic(50.0, 4, 5.0);
+        assert!([50.0, 100.0, 150.0, 200.0].iter().all(|&f| hum.gain(f) < 1e-9));
+        assert!(hum.gain(1000.0) > 0.9);
+
+        let both = LowPass { cutoff: 1000.0, q: 0.707 }.then(HighPass { cutoff: 100.0, q: 0.707 });
+        assert!(close(both.gain(300.0), LowPass { cutoff: 1000.0, q: 0.707 }.gain(300.0) * HighPass { cutoff: 100.0, q: 0.707 }.gain(300.0), 1e-12));
+        assert!(close((|_: f64| 0.25).plus(|_: f64| 0.5).gain(1.0), 0.75, 1e-12));
+    }
+
+    #[test]
+    fn test_windows() {
+        for w in [Window::Hann, Window::Blackman] {
+            assert!(close(w.gain(0.0), 0.0, 1e-12) && close(w.gain(0.5), 1.0, 1e-12));
+        }
+        assert!(close(Window::Hamming.gain(1.0), 0.08, 1e-12));
+        assert_eq!(Window::Tukey(0.2).gain(0.3), 1.0);
+        assert!(close(Window::Tukey(0.2).gain(0.05), 0.5, 1e-12));
+        assert!(close(Window::Gaussian(0.1).gain(0.6), (-0.5_f64).exp(), 1e-12));
+        assert!(close(Window::Decay(3.0).gain(1.0), (-3.0_f64).exp(), 1e-12));
+    }
+
+    #[test]
+    fn test_parse_stages() {
+        let stages = parse_stages("lowpass:800:0.7,vowel:o,pink,notch:50:3:5").unwrap();
+        assert_eq!(stages, vec![
+            Stage::LowPass { cutoff: 800.0, q: 0.7 },
+            Stage::Vowel { vowel: 'o' },
+            Stage::Tilt { db_per_octave: -3.0, reference: 1000.0 },
+            Stage::Notches { freqs: vec![50.0, 100.0, 150.0], q: 5.0 },
+        ]);
+        assert_eq!(parse_stages("tilt:-6").unwrap(), parse_stages("brown").unwrap());
+        assert_eq!(parse_stages("").unwrap(), vec![]);
+        assert!(parse_stages("lowpass:800").is_err());
+        assert!(parse_stages("vowel:y").is_err());
+        assert!(parse_stages("chorus:1").is_err());
+        for bad in ["lowpass:0:0.7", "highpass:-80:0.7", "bandpass:1000:0", "lowpass:NaN:1", "lowpass:inf:1", "notch:-50:3:5", "notch:50:3:0"] {
+            assert!(parse_stages(bad).is_err(), "{}", bad);
+        }
+        assert!(parse_stages("notch:50:0:5").is_err());
+        assert!(parse_stages("notch:50:1000000000:5").is_err());
+        assert!(parse_stages(&format!("notch:50:{}:5", MAX_NOTCHES)).is_ok());
+
+        assert_eq!("tukey:0.2".parse::<Window>(), Ok(Window::Tukey(0.2)));
+        assert_eq!("hann".parse::<Window>(), Ok(Window::Hann));
+        assert!("decay".parse::<Window>().is_err());
+        assert!("gaussian:0".parse::<Window>().is_err());
+        assert!("tukey:-0.5".parse::<Window>().is_err());
+        assert!("decay:inf".parse::<Window>().is_err());
+    }
+
+    #[test]
+    fn test_presets_stay_real() {
+        let mut sub = Subtractive::with_len(4410, Looping::Seamless);
+        sub.new_preset(window(Window::Hann), bins(LowPass { cutoff: 2000.0, q: 2.0 }.then(Tilt::pink())));
+        sub.new_preset(window(Window::Decay(4.0)), stereo(Formant::vowel('o').unwrap(), Comb { freq: 220.0, feedback: 0.8 }));
+        for (a, b) in &sub.preset {
+            assert!(a.iter().chain(b).all(|c| c.im.abs() < 1e-9 && c.re.is_finite()));
+            assert_ne!(a, b);
+        }
+        // the Hann window leaves the ends quiet
+        let (a, _) = &sub.preset[0];
+        assert!(a[0].re.abs() < a.iter().fold(0_f64, |m, c| m.max(c.re.abs())) * 0.2);
+    }
+}
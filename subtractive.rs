@@ -1,62 +1,582 @@
-use hound;
 use rand::prelude::*;
+use rand_chacha::ChaCha8Rng;
 use rustfft::{num_complex::Complex, Fft, FftPlanner};
-use std::f64::consts::PI;
-use std::i16;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::path::Path;
+use std::str::FromStr;
 use std::sync::Arc;
+use std::{fmt, fs, io, thread};
 
+use super::spectral::{self, Stage, Window};
 use super::SAMPLE_RATE;
 
 type Pair<T> = (T, T);
+type Plan = Pair<Arc<dyn Fft<f64>>>;
+type Shape = Box<dyn Fn(&mut Complex<f64>, f64, f64)>;
+
+#[derive(Debug)]
+pub enum PresetError {
+    Missing(usize),
+    OutOfRange { index: usize, count: usize },
+    Format(String),
+    Io(io::Error),
+    Wav(hound::Error),
+}
+
+impl fmt::Display for PresetError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PresetError::Missing(i) => write!(f, "preset {} has neither a recipe nor samples", i),
+            PresetError::OutOfRange { index, count } => write!(f, "no preset {} (there are {})", index, count),
+            PresetError::Format(msg) => write!(f, "preset file error: {}", msg),
+            PresetError::Io(e) => write!(f, "io error: {}", e),
+            PresetError::Wav(e) => write!(f, "wav error: {}", e),
+        }
+    }
+}
+
+impl Error for PresetError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            PresetError::Io(e) => Some(e),
+            PresetError::Wav(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for PresetError {
+    fn from(e: io::Error) -> Self {
+        PresetError::Io(e)
+    }
+}
+
+impl From<hound::Error> for PresetError {
+    fn from(e: hound::Error) -> Self {
+        PresetError::Wav(e)
+    }
+}
+
+// How the two ends of a preset meet when it is played on repeat.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Looping {
+    // Filtered circularly, so the last sample runs smoothly into the first.
+    Seamless,
+    // Filtered over a zero-padded buffer twice as long, so nothing from the
+    // end wraps around into the start.
+    OneShot,
+}
+
+impl FromStr for Looping {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Looping, String> {
+        match s {
+            "seamless" => Ok(Looping::Seamless),
+            "one-shot" => Ok(Looping::OneShot),
+            _ => Err(format!("unknown looping '{}', expected seamless or one-shot", s)),
+        }
+    }
+}
+
+// Everything needed to make a preset again: the same seed, length, window
+// and filter stages always give the same samples.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Recipe {
+    pub seed: u64,
+    pub len: usize,
+    pub looping: Looping,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub window: Option<Window>,
+    #[serde(default)]
+    pub left: Vec<Stage>,
+    // the left stages again if not given
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub right: Option<Vec<Stage>>,
+}
+
+impl Recipe {
+    // Unfiltered, unwindowed noise.
+    pub fn new(seed: u64, len: usize, looping: Looping) -> Self {
+        Recipe { seed, len, looping, window: None, left: vec![], right: None }
+    }
+}
+
+// One entry of a preset file. Samples are the real parts of the buffers;
+// a preset with a recipe and no samples is regenerated on load.
+#[derive(Serialize, Deserialize)]
+struct Saved {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    recipe: Option<Recipe>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    samples: Option<Pair<Vec<f64>>>,
+}
+
+// `len` and `looping` are the settings for presets made after loading;
+// files without them load with the `Subtractive::new` defaults.
+#[derive(Serialize, Deserialize)]
+struct PresetFile {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    len: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    looping: Option<Looping>,
+    presets: Vec<Saved>,
+}
 
 pub struct Subtractive {
-    pub fft: Arc<dyn Fft<f64>>,
-    pub ifft: Arc<dyn Fft<f64>>,
-    pub preset: Vec<Pair<[Complex<f64>; SAMPLE_RATE as usize]>>,
+    // Samples per preset; any length works, and changing it only affects
+    // presets made afterwards.
+    pub len: usize,
+    pub looping: Looping,
+    pub preset: Vec<Pair<Vec<Complex<f64>>>>,
+    // for the batch methods and `load`; the presets are the same whatever
+    // it is
+    pub threads: usize,
+    // how each preset was made, if it came from `add`
+    recipes: Vec<Option<Recipe>>,
+    planner: FftPlanner<f64>,
+}
+
+impl Default for Subtractive {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Subtractive {
+    // One-second seamless presets.
     pub fn new() -> Self {
-        let mut planner = FftPlanner::new();
-        let fft = planner.plan_fft_forward(SAMPLE_RATE as usize);
-        let ifft = planner.plan_fft_inverse(SAMPLE_RATE as usize);
+        Self::with_len(SAMPLE_RATE as usize, Looping::Seamless)
+    }
+
+    pub fn with_len(len: usize, looping: Looping) -> Self {
         Self {
-            fft,
-            ifft,
+            len: len.max(1),
+            looping,
             preset: vec![],
+            threads: thread::available_parallelism().map_or(1, |n| n.get()),
+            recipes: vec![],
+            planner: FftPlanner::new(),
         }
     }
 
+    // Makes a stereo pair of `len` noise samples, shapes it in time with
+    // `g(sample, channel, x)` where x = (2i - 1) / len, filters it with
+    // `f(bin, channel, hz)` and stores it as the next preset. `spectral::window`
+    // and `spectral::bins` build `g` and `f` from named shapes and filters.
+    // Closures can't be saved, so `save` keeps the samples of these presets;
+    // use `add` for presets that can be rebuilt from a recipe.
+    // The CLI only makes presets from saved recipes, so this and the other
+    // code-facing constructors below have no caller in the binary.
+    #[allow(dead_code)]
     pub fn new_preset<G, F>(&mut self, g: G, f: F)
     where
         G: Fn(&mut Complex<f64>, f64, f64),
         F: Fn(&mut Complex<f64>, f64, f64),
     {
-        let mut rng = rand::thread_rng();
-        let mut noise_a = [Complex::default(); SAMPLE_RATE as usize];
-        let mut noise_b = [Complex::default(); SAMPLE_RATE as usize];
-        for i in 0..SAMPLE_RATE as usize {
-            noise_a[i].re = rng.gen();
-            noise_b[i].re = rng.gen();
-            let x = (i as f64 * 2.0 - 1.0) / SAMPLE_RATE as f64;
-            g(&mut noise_a[i], 0.0, x);
-            g(&mut noise_b[i], 1.0, x);
-        }
-        self.fft.process(&mut noise_a);
-        self.fft.process(&mut noise_b);
-        let norm = (SAMPLE_RATE as f64).sqrt();
-        for i in 0..SAMPLE_RATE as usize {
-            noise_a[i] /= norm;
-            noise_b[i] /= norm;
-            f(&mut noise_a[i], 0.0, i as f64);
-            f(&mut noise_b[i], 1.0, i as f64);
-        }
-        self.ifft.process(&mut noise_a);
-        self.ifft.process(&mut noise_b);
-        for i in 0..SAMPLE_RATE as usize {
-            noise_a[i] /= norm;
-            noise_b[i] /= norm;
-        }
-        self.preset.push((noise_a, noise_b));
+        self.new_preset_seeded(rand::thread_rng().gen(), g, f);
+    }
+
+    // `new_preset` with noise from `seed`, so the same closures give the
+    // same preset every time.
+    #[allow(dead_code)]
+    pub fn new_preset_seeded<G, F>(&mut self, seed: u64, g: G, f: F)
+    where
+        G: Fn(&mut Complex<f64>, f64, f64),
+        F: Fn(&mut Complex<f64>, f64, f64),
+    {
+        let plan = self.plan(self.len, self.looping);
+        self.preset.push(generate(self.len, self.looping, seed, &plan, g, f));
+        self.recipes.push(None);
+    }
+
+    // `new_preset_seeded` once per seed, spread over `threads` threads. Each
+    // preset depends only on its seed, so the result is bit-identical to
+    // making them one by one, in the same order.
+    #[allow(dead_code)]
+    pub fn new_presets<G, F>(&mut self, seeds: &[u64], g: G, f: F)
+    where
+        G: Fn(&mut Complex<f64>, f64, f64) + Sync,
+        F: Fn(&mut Complex<f64>, f64, f64) + Sync,
+    {
+        let (len, looping) = (self.len, self.looping);
+        let shapes = vec![(len, looping); seeds.len()];
+        let presets = self.batch(&shapes, |i, plan| generate(len, looping, seeds[i], plan, &g, &f));
+        self.recipes.extend(presets.iter().map(|_| None));
+        self.preset.extend(presets);
+    }
+
+    // Makes the preset `recipe` describes and remembers the recipe, whatever
+    // `len` and `looping` are set to.
+    #[allow(dead_code)]
+    pub fn add(&mut self, recipe: Recipe) {
+        let plan = self.plan(recipe.len, recipe.looping);
+        self.preset.push(render(&recipe, &plan));
+        self.recipes.push(Some(recipe));
+    }
+
+    // `add` for a whole bank of recipes, in parallel like `new_presets`.
+    pub fn add_all(&mut self, recipes: Vec<Recipe>) {
+        let shapes: Vec<_> = recipes.iter().map(|r| (r.len, r.looping)).collect();
+        let presets = self.batch(&shapes, |i, plan| render(&recipes[i], plan));
+        self.preset.extend(presets);
+        self.recipes.extend(recipes.into_iter().map(Some));
+    }
+
+    pub fn recipe(&self, index: usize) -> Option<&Recipe> {
+        self.recipes.get(index).and_then(Option::as_ref)
+    }
+
+    // The transforms for one preset, planned once per size and shared from
+    // then on.
+    fn plan(&mut self, len: usize, looping: Looping) -> Plan {
+        let n = size(len, looping);
+        (self.planner.plan_fft_forward(n), self.planner.plan_fft_inverse(n))
+    }
+
+    // Runs `make(i, plan)` for every (len, looping) in `shapes`, dealing the
+    // jobs out round-robin to `threads` threads, and returns the presets in
+    // order.
+    fn batch<M>(&mut self, shapes: &[(usize, Looping)], make: M) -> Vec<Pair<Vec<Complex<f64>>>>
+    where
+        M: Fn(usize, &Plan) -> Pair<Vec<Complex<f64>>> + Sync,
+    {
+        let plans: Vec<Plan> = shapes.iter().map(|&(len, looping)| self.plan(len, looping)).collect();
+        let mut out = vec![None; shapes.len()];
+        let threads = self.threads.max(1);
+        let mut queues: Vec<Vec<(usize, &mut Option<_>)>> = (0..threads).map(|_| vec![]).collect();
+        for (i, slot) in out.iter_mut().enumerate() {
+            queues[i % threads].push((i, slot));
+        }
+
+        let (make, plans) = (&make, &plans);
+        thread::scope(|scope| {
+            for queue in queues {
+                scope.spawn(move || {
+                    for (i, slot) in queue {
+                        *slot = Some(make(i, &plans[i]));
+                    }
+                });
+            }
+        });
+        out.into_iter().map(|preset| preset.expect("every job ran")).collect()
+    }
+
+    // Writes `len`, `looping` and every preset to a JSON file: the recipe
+    // where there is one, and the samples too if `with_samples` or if there
+    // is no recipe to rebuild them from.
+    pub fn save(&self, path: &Path, with_samples: bool) -> Result<(), PresetError> {
+        let presets = self.preset.iter().enumerate()
+            .map(|(i, (a, b))| {
+                let recipe = self.recipe(i).cloned();
+                let samples = (with_samples || recipe.is_none())
+                    .then(|| (a.iter().map(|c| c.re).collect(), b.iter().map(|c| c.re).collect()));
+                Saved { recipe, samples }
+            })
+            .collect();
+        let text = serde_json::to_string(&PresetFile { len: Some(self.len), looping: Some(self.looping), presets }).map_err(|e| PresetError::Format(e.to_string()))?;
+        fs::write(path, text)?;
+        Ok(())
+    }
+
+    // Reads a file written by `save`. Saved samples are used as they are,
+    // once both channels are checked to be the same, non-zero length (and the
+    // recipe's, if there is one); presets with only a recipe are generated
+    // again, together, like `add_all`.
+    pub fn load(path: &Path) -> Result<Self, PresetError> {
+        let text = fs::read_to_string(path)?;
+        let file: PresetFile = serde_json::from_str(&text).map_err(|e| PresetError::Format(e.to_string()))?;
+        let mut sub = Subtractive::new();
+        sub.len = file.len.map_or(sub.len, |len| len.max(1));
+        sub.looping = file.looping.unwrap_or(sub.looping);
+        let mut pending = vec![];
+        for (i, saved) in file.presets.into_iter().enumerate() {
+            let pair = match (&saved.recipe, saved.samples) {
+                (recipe, Some((a, b))) => {
+                    let expected = recipe.as_ref().map_or(a.len(), |r| r.len);
+                    if a.is_empty() || a.len() != b.len() || a.len() != expected {
+                        return Err(PresetError::Format(format!(
+                            "preset {} has {} and {} samples, expected the same non-zero length{}",
+                            i,
+                            a.len(),
+                            b.len(),
+                            recipe.as_ref().map_or(String::new(), |r| format!(" of {}", r.len))
+                        )));
+                    }
+                    let complex = |v: Vec<f64>| v.into_iter().map(|re| Complex::new(re, 0.0)).collect();
+                    (complex(a), complex(b))
+                }
+                (Some(recipe), None) => {
+                    pending.push((i, recipe.clone()));
+                    (vec![], vec![])
+                }
+                (None, None) => return Err(PresetError::Missing(i)),
+            };
+            sub.preset.push(pair);
+            sub.recipes.push(saved.recipe);
+        }
+
+        let shapes: Vec<_> = pending.iter().map(|(_, r)| (r.len, r.looping)).collect();
+        let presets = sub.batch(&shapes, |j, plan| render(&pending[j].1, plan));
+        for ((i, _), pair) in pending.iter().zip(presets) {
+            sub.preset[*i] = pair;
+        }
+        Ok(sub)
+    }
+
+    // Writes preset `index` as a 16-bit stereo WAV. Raw noise sits on a DC
+    // offset, so each channel is centred and the pair scaled to full scale.
+    pub fn export_wav(&self, index: usize, path: &Path) -> Result<(), PresetError> {
+        let (a, b) = self.preset.get(index).ok_or(PresetError::OutOfRange { index, count: self.preset.len() })?;
+        let centre = |v: &[Complex<f64>]| {
+            let mean = v.iter().map(|c| c.re).sum::<f64>() / v.len().max(1) as f64;
+            v.iter().map(|c| c.re - mean).collect::<Vec<_>>()
+        };
+        let (left, right) = (centre(a), centre(b));
+        let peak = left.iter().chain(&right).fold(0.0_f64, |m, x| m.max(x.abs()));
+        let scale = if peak > 0.0 { 1.0 / peak } else { 0.0 };
+        let spec = hound::WavSpec {
+            channels: 2,
+            sample_rate: SAMPLE_RATE,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut writer = hound::WavWriter::create(path, spec)?;
+        for (l, r) in left.iter().zip(&right) {
+            writer.write_sample((l * scale * i16::MAX as f64) as i16)?;
+            writer.write_sample((r * scale * i16::MAX as f64) as i16)?;
+        }
+        writer.finalize()?;
+        Ok(())
+    }
+}
+
+fn size(len: usize, looping: Looping) -> usize {
+    match looping {
+        Looping::Seamless => len.max(1),
+        Looping::OneShot => 2 * len.max(1),
+    }
+}
+
+fn render(recipe: &Recipe, plan: &Plan) -> Pair<Vec<Complex<f64>>> {
+    let filter: Shape = match &recipe.right {
+        Some(right) => Box::new(spectral::stereo(recipe.left.clone(), right.clone())),
+        None => Box::new(spectral::bins(recipe.left.clone())),
+    };
+    match recipe.window {
+        Some(shape) => generate(recipe.len, recipe.looping, recipe.seed, plan, spectral::window(shape), filter),
+        None => generate(recipe.len, recipe.looping, recipe.seed, plan, |_, _, _| {}, filter),
+    }
+}
+
+fn generate<G, F>(len: usize, looping: Looping, seed: u64, plan: &Plan, g: G, f: F) -> Pair<Vec<Complex<f64>>>
+where
+    G: Fn(&mut Complex<f64>, f64, f64),
+    F: Fn(&mut Complex<f64>, f64, f64),
+{
+    let len = len.max(1);
+    let n = size(len, looping);
+    let (fft, ifft) = plan;
+
+    let mut rng = ChaCha8Rng::seed_from_u64(seed);
+    let mut noise_a = vec![Complex::default(); n];
+    let mut noise_b = vec![Complex::default(); n];
+    for i in 0..len {
+        noise_a[i].re = rng.gen();
+        noise_b[i].re = rng.gen();
+        let x = (i as f64 * 2.0 - 1.0) / len as f64;
+        g(&mut noise_a[i], 0.0, x);
+        g(&mut noise_b[i], 1.0, x);
+    }
+    fft.process(&mut noise_a);
+    fft.process(&mut noise_b);
+    let norm = (n as f64).sqrt();
+    let bin_hz = SAMPLE_RATE as f64 / n as f64;
+    for i in 0..n {
+        noise_a[i] /= norm;
+        noise_b[i] /= norm;
+        f(&mut noise_a[i], 0.0, i as f64 * bin_hz);
+        f(&mut noise_b[i], 1.0, i as f64 * bin_hz);
+    }
+    ifft.process(&mut noise_a);
+    ifft.process(&mut noise_b);
+    noise_a.truncate(len);
+    noise_b.truncate(len);
+    for i in 0..len {
+        noise_a[i] /= norm;
+        noise_b[i] /= norm;
+    }
+    (noise_a, noise_b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // keeps bins below 200 Hz (and their negative-frequency mirrors)
+    fn low_pass(bin: &mut Complex<f64>, _: f64, hz: f64) {
+        if hz > 200.0 && hz < SAMPLE_RATE as f64 - 200.0 {
+            *bin = Complex::default();
+        }
+    }
+
+    fn energy(samples: &[Complex<f64>]) -> f64 {
+        samples.iter().map(|c| c.norm_sqr()).sum::<f64>() / samples.len() as f64
+    }
+
+    #[test]
+    fn test_any_length() {
+        let mut sub = Subtractive::with_len(1000, Looping::Seamless);
+        sub.new_preset(|_, _, _| {}, |_, _, _| {});
+        sub.len = 3 * SAMPLE_RATE as usize / 2;
+        sub.looping = Looping::OneShot;
+        sub.new_preset(|_, _, _| {}, |_, _, _| {});
+        assert_eq!(sub.preset[0].0.len(), 1000);
+        assert_eq!(sub.preset[1].1.len(), 66150);
+        // with a flat filter the noise comes back unchanged, in [0, 1)
+        for (a, b) in &sub.preset {
+            assert!(a.iter().chain(b).all(|c| (-1e-9..1.0 + 1e-9).contains(&c.re) && c.im.abs() < 1e-9));
+        }
+    }
+
+    #[test]
+    fn test_seamless_wraps_and_one_shot_does_not() {
+        // noise only in the last tenth of the buffer, then smoothed
+        let late = |c: &mut Complex<f64>, _: f64, x: f64| {
+            if x < 1.8 {
+                *c = Complex::default();
+            }
+        };
+        let len = 8820;
+        let mut seamless = Subtractive::with_len(len, Looping::Seamless);
+        seamless.new_preset(late, low_pass);
+        let mut one_shot = Subtractive::with_len(len, Looping::OneShot);
+        one_shot.new_preset(late, low_pass);
+
+        let (a, _) = &seamless.preset[0];
+        let (b, _) = &one_shot.preset[0];
+        // the smoothed tail carries on across the seam...
+        assert!(energy(&a[..200]) > energy(&a[len - 200..]) * 1e-3);
+        assert!((a[len - 1] - a[0]).norm() < 0.05);
+        // ...but a one-shot starts quiet
+        assert!(energy(&b[..200]) < energy(&b[len - 200..]) * 1e-5);
+        assert!(energy(&a[..200]) > energy(&b[..200]) * 1e3);
+    }
+
+    fn temp(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("subtractive_{}_{}", std::process::id(), name))
+    }
+
+    fn recipe(seed: u64) -> Recipe {
+        Recipe {
+            window: Some(Window::Hann),
+            left: vec![Stage::LowPass { cutoff: 800.0, q: 0.7 }],
+            right: Some(vec![Stage::Vowel { vowel: 'o' }]),
+            ..Recipe::new(seed, 4410, Looping::OneShot)
+        }
+    }
+
+    #[test]
+    fn test_recipes_are_reproducible() {
+        let mut sub = Subtractive::new();
+        sub.add(recipe(7));
+        sub.add(recipe(7));
+        sub.add(recipe(8));
+        assert_eq!(sub.preset[0], sub.preset[1]);
+        assert_ne!(sub.preset[0].0, sub.preset[2].0);
+        assert_eq!(sub.preset[0].0.len(), 4410);
+        // the channels have different filters
+        assert_ne!(sub.preset[0].0, sub.preset[0].1);
+        assert_eq!(sub.recipe(2), Some(&recipe(8)));
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let mut sub = Subtractive::with_len(2000, Looping::OneShot);
+        sub.add(recipe(1));
+        sub.new_preset(|_, _, _| {}, low_pass);
+        let path = temp("presets.json");
+        sub.save(&path, false).unwrap();
+        let loaded = Subtractive::load(&path).unwrap();
+        // the settings for new presets come back too
+        assert_eq!((loaded.len, loaded.looping), (2000, Looping::OneShot));
+        // the recipe is rebuilt, the closure preset comes back from its samples
+        assert_eq!(loaded.preset[0], sub.preset[0]);
+        assert_eq!(loaded.recipe(0), sub.recipe(0));
+        assert_eq!(loaded.recipe(1), None);
+        // JSON keeps samples to within a rounding step
+        let same = |x: &[Complex<f64>], y: &[Complex<f64>]| x.iter().zip(y).all(|(x, y)| (x.re - y.re).abs() < 1e-12);
+        assert!(same(&loaded.preset[1].0, &sub.preset[1].0));
+
+        // with samples, the file is much bigger but loads the same
+        let small = fs::metadata(&path).unwrap().len();
+        sub.save(&path, true).unwrap();
+        assert!(fs::metadata(&path).unwrap().len() > small);
+        let loaded = Subtractive::load(&path).unwrap();
+        assert!(same(&loaded.preset[0].1, &sub.preset[0].1));
+
+        // older files without the settings get the defaults
+        fs::write(&path, r#"{"presets": []}"#).unwrap();
+        let loaded = Subtractive::load(&path).unwrap();
+        assert_eq!((loaded.len, loaded.looping), (SAMPLE_RATE as usize, Looping::Seamless));
+
+        fs::write(&path, r#"{"presets": [{}]}"#).unwrap();
+        assert!(matches!(Subtractive::load(&path), Err(PresetError::Missing(0))));
+
+        // channels must match each other, and the recipe if there is one
+        for samples in [r#"[[0.1, 0.2], [0.3]]"#, r#"[[], []]"#] {
+            fs::write(&path, format!(r#"{{"presets": [{{"samples": {}}}]}}"#, samples)).unwrap();
+            assert!(matches!(Subtractive::load(&path), Err(PresetError::Format(_))), "{samples}");
+        }
+        let recipe = serde_json::to_string(&recipe(1)).unwrap();
+        fs::write(&path, format!(r#"{{"presets": [{{"recipe": {}, "samples": [[0.1], [0.2]]}}]}}"#, recipe)).unwrap();
+        assert!(matches!(Subtractive::load(&path), Err(PresetError::Format(_))));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_export_wav() {
+        let mut sub = Subtractive::new();
+        sub.add(recipe(3));
+        let path = temp("preset.wav");
+        sub.export_wav(0, &path).unwrap();
+        let mut reader = hound::WavReader::open(&path).unwrap();
+        assert_eq!(reader.spec().channels, 2);
+        assert_eq!(reader.duration(), 4410);
+        let samples: Vec<i16> = reader.samples::<i16>().map(Result::unwrap).collect();
+        assert!(samples.iter().any(|s| s.unsigned_abs() > 32000));
+        assert!(samples.chunks(2).any(|f| f[0] != f[1]));
+        fs::remove_file(&path).unwrap();
+        assert!(matches!(sub.export_wav(1, &path), Err(PresetError::OutOfRange { index: 1, count: 1 })));
+    }
+
+    #[test]
+    fn test_batches_match_one_by_one() {
+        let seeds = [3, 1, 4, 1, 5, 9, 2, 6];
+        let mut one = Subtractive::with_len(3000, Looping::OneShot);
+        for &seed in &seeds {
+            one.new_preset_seeded(seed, spectral::window(Window::Decay(3.0)), low_pass);
+        }
+        for threads in [1, 3, 16] {
+            let mut batch = Subtractive::with_len(3000, Looping::OneShot);
+            batch.threads = threads;
+            batch.new_presets(&seeds, spectral::window(Window::Decay(3.0)), low_pass);
+            assert_eq!(batch.preset, one.preset);
+        }
+        // repeated seeds repeat presets
+        assert_eq!(one.preset[1], one.preset[3]);
+
+        let recipes: Vec<_> = (0..6).map(|i| Recipe { len: 1000 + 500 * i as usize, ..recipe(i) }).collect();
+        let mut added = Subtractive::new();
+        for recipe in recipes.clone() {
+            added.add(recipe);
+        }
+        let mut all = Subtractive::new();
+        all.threads = 4;
+        all.add_all(recipes);
+        assert_eq!(all.preset, added.preset);
+        assert_eq!(all.recipe(5), added.recipe(5));
     }
 }
@@ -0,0 +1,217 @@
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+#[derive(Debug)]
+pub enum TuningError {
+    UnknownScale(String),
+    Scala(String),
+    Io(io::Error),
+}
+
+impl fmt::Display for TuningError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TuningError::UnknownScale(name) => write!(
+                f,
+                "unknown scale '{}', expected just-major, just-minor, pythagorean, harmonic, N-tet or a .scl file",
+                name
+            ),
+            TuningError::Scala(msg) => write!(f, "scala error: {}", msg),
+            TuningError::Io(e) => write!(f, "io error: {}", e),
+        }
+    }
+}
+
+impl Error for TuningError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            TuningError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for TuningError {
+    fn from(e: io::Error) -> Self {
+        TuningError::Io(e)
+    }
+}
+
+const JUST_MAJOR: [f64; 7] = [9.0 / 8.0, 5.0 / 4.0, 4.0 / 3.0, 3.0 / 2.0, 5.0 / 3.0, 15.0 / 8.0, 2.0];
+const JUST_MINOR: [f64; 7] = [9.0 / 8.0, 6.0 / 5.0, 4.0 / 3.0, 3.0 / 2.0, 8.0 / 5.0, 9.0 / 5.0, 2.0];
+const PYTHAGOREAN: [f64; 7] = [9.0 / 8.0, 81.0 / 64.0, 4.0 / 3.0, 3.0 / 2.0, 27.0 / 16.0, 243.0 / 128.0, 2.0];
+// harmonics 8 to 16 folded into one octave
+const HARMONIC: [f64; 8] = [9.0 / 8.0, 10.0 / 8.0, 11.0 / 8.0, 12.0 / 8.0, 13.0 / 8.0, 14.0 / 8.0, 15.0 / 8.0, 2.0];
+
+// A periodic scale in the Scala sense: `steps` are the ratios of degrees
+// 1..=n to degree 0, and the last one is the period (usually 2/1) that
+// degrees outside 0..n repeat at.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Scale {
+    pub name: String,
+    pub steps: Vec<f64>,
+}
+
+impl Scale {
+    pub fn new(name: &str, steps: &[f64]) -> Result<Scale, TuningError> {
+        if steps.is_empty() {
+            return Err(TuningError::Scala(format!("'{}' has no steps", name)));
+        }
+        if let Some(bad) = steps.iter().find(|r| !(r.is_finite() && **r > 0.0)) {
+            return Err(TuningError::Scala(format!("'{}' has a non-positive ratio {}", name, bad)));
+        }
+        if steps[steps.len() - 1] <= 1.0 {
+            return Err(TuningError::Scala(format!("'{}' has a period of {}, expected above 1", name, steps[steps.len() - 1])));
+        }
+        Ok(Scale { name: name.to_string(), steps: steps.to_vec() })
+    }
+
+    pub fn equal(divisions: u32) -> Scale {
+        let steps = (1..=divisions).map(|k| 2_f64.powf(k as f64 / divisions as f64)).collect();
+        Scale { name: format!("{}-tet", divisions), steps }
+    }
+
+    // The built-in scales: just-major, just-minor, pythagorean, harmonic and
+    // `N-tet` for any N.
+    pub fn named(name: &str) -> Result<Scale, TuningError> {
+        let steps: &[f64] = match name {
+            "just-major" => &JUST_MAJOR,
+            "just-minor" => &JUST_MINOR,
+            "pythagorean" => &PYTHAGOREAN,
+            "harmonic" => &HARMONIC,
+            _ => {
+                return match name.strip_suffix("-tet").and_then(|n| n.parse::<u32>().ok()) {
+                    Some(divisions) if divisions > 0 => Ok(Scale::equal(divisions)),
+                    _ => Err(TuningError::UnknownScale(name.to_string())),
+                };
+            }
+        };
+        Scale::new(name, steps)
+    }
+
+    // A built-in name, or a path to a Scala file if it ends in `.scl`.
+    pub fn lookup(spec: &str) -> Result<Scale, TuningError> {
+        if spec.ends_with(".scl") {
+            Scale::load(Path::new(spec))
+        } else {
+            Scale::named(spec)
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<Scale, TuningError> {
+        let mut scale = Scale::from_scl(&fs::read_to_string(path)?)?;
+        if scale.name.is_empty() {
+            scale.name = path.file_stem().map_or(String::new(), |s| s.to_string_lossy().into_owned());
+        }
+        Ok(scale)
+    }
+
+    // Parses the Scala format (https://www.huygens-fokker.org/scala/scl_format.html):
+    // `!` comments, a description line, a note count, then one pitch per line,
+    // in cents if it contains a period and as a ratio or integer otherwise.
+    pub fn from_scl(text: &str) -> Result<Scale, TuningError> {
+        let mut lines = text.lines().filter(|l| !l.starts_with('!'));
+        let description = lines.next().ok_or_else(|| TuningError::Scala("missing description".into()))?.trim();
+        let count = lines
+            .next()
+            .and_then(|l| l.split_whitespace().next())
+            .and_then(|n| n.parse::<usize>().ok())
+            .ok_or_else(|| TuningError::Scala("missing note count".into()))?;
+        let steps = lines
+            .filter_map(|l| l.split_whitespace().next())
+            .take(count)
+            .map(parse_pitch)
+            .collect::<Result<Vec<f64>, TuningError>>()?;
+        if steps.len() != count {
+            return Err(TuningError::Scala(format!("expected {} notes, found {}", count, steps.len())));
+        }
+        Scale::new(description, &steps)
+    }
+
+    pub fn period(&self) -> f64 {
+        self.steps[self.steps.len() - 1]
+    }
+
+    // Ratio of `degree` to degree 0; negative degrees go down from the root.
+    pub fn ratio(&self, degree: i32) -> f64 {
+        let n = self.steps.len() as i32;
+        let step = match degree.rem_euclid(n) {
+            0 => 1.0,
+            k => self.steps[k as usize - 1],
+        };
+        step * self.period().powi(degree.div_euclid(n))
+    }
+
+    pub fn ratios(&self, degrees: &[i32]) -> Vec<f64> {
+        degrees.iter().map(|&d| self.ratio(d)).collect()
+    }
+}
+
+fn parse_pitch(token: &str) -> Result<f64, TuningError> {
+    let bad = || TuningError::Scala(format!("bad pitch '{}'", token));
+    if token.contains('.') {
+        let cents = token.parse::<f64>().map_err(|_| bad())?;
+        return Ok(2_f64.powf(cents / 1200.0));
+    }
+    match token.split_once('/') {
+        Some((num, den)) => {
+            let num = num.parse::<f64>().map_err(|_| bad())?;
+            let den = den.parse::<f64>().map_err(|_| bad())?;
+            if den == 0.0 {
+                return Err(bad());
+            }
+            Ok(num / den)
+        }
+        None => token.parse::<f64>().map_err(|_| bad()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_degrees_wrap_at_the_period() {
+        let minor = Scale::named("just-minor").unwrap();
+        assert_eq!(minor.steps.len(), 7);
+        assert_eq!(minor.ratio(0), 1.0);
+        assert_eq!(minor.ratio(4), 1.5);
+        assert_eq!(minor.ratio(11), 3.0);
+        assert_eq!(minor.ratio(-3), 0.75);
+        assert_eq!(minor.ratio(-10), 0.375);
+        assert_eq!(minor.ratio(7), 2.0);
+
+        let tet = Scale::named("12-tet").unwrap();
+        assert!((tet.ratio(7) - 1.4983070768766815).abs() < 1e-12);
+        assert!((tet.ratio(-12) - 0.5).abs() < 1e-12);
+        assert!(matches!(Scale::named("0-tet"), Err(TuningError::UnknownScale(_))));
+        assert!(matches!(Scale::named("lydian"), Err(TuningError::UnknownScale(_))));
+    }
+
+    #[test]
+    fn test_scala_parsing() {
+        let text = "! meantone.scl\n!\nQuarter-comma meantone, partial\n 4\n!\n 193.15686\n 5/4 major third\n 696.57843\n 2\n";
+        let scale = Scale::from_scl(text).unwrap();
+        assert_eq!(scale.name, "Quarter-comma meantone, partial");
+        assert_eq!(scale.steps.len(), 4);
+        assert!((scale.ratio(1) - 1.1180339887).abs() < 1e-7);
+        assert_eq!(scale.ratio(2), 1.25);
+        assert!((scale.ratio(3) - 1.4953487812).abs() < 1e-7);
+        assert_eq!(scale.ratio(4), 2.0);
+
+        assert!(matches!(Scale::from_scl("x\n3\n9/8\n3/2\n"), Err(TuningError::Scala(_))));
+        assert!(matches!(Scale::from_scl("x\n1\n3/0\n"), Err(TuningError::Scala(_))));
+        assert!(matches!(Scale::from_scl("x\n1\n1/2\n"), Err(TuningError::Scala(_))));
+    }
+
+    #[test]
+    fn test_load_bundled_scale() {
+        let scale = Scale::lookup(concat!(env!("CARGO_MANIFEST_DIR"), "/scales/meantone.scl")).unwrap();
+        assert_eq!(scale.steps.len(), 12);
+        assert_eq!(scale.period(), 2.0);
+        assert_eq!(scale.ratio(4), 1.25);
+    }
+}